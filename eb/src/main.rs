@@ -1,3 +1,36 @@
-fn main() {
-    println!("Hello, world!");
+extern crate eb_ast as ast;
+extern crate eb_codegen_fast as codegen;
+extern crate eb_diagnostics as diagnostics;
+extern crate eb_lexer as lexer;
+extern crate eb_parser as parser;
+extern crate eb_vm as vm;
+extern crate eb_vm_ctx as vm_ctx;
+
+mod repl;
+mod run;
+
+use diagnostics::Diagnostic;
+use lexer::source::SourceMap;
+use std::{env, process::ExitCode};
+
+const USAGE: &str = "\
+usage: eb run <file.eb> [args...]
+       eb repl";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("run") if args.len() >= 2 => run::run(&args[1], &args[2..]),
+        Some("repl") if args.len() == 1 => match repl::run() {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                Diagnostic::error(format!("{:#}", e)).emit(&SourceMap::default());
+                ExitCode::FAILURE
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            ExitCode::from(2)
+        }
+    }
 }
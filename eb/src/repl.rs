@@ -0,0 +1,270 @@
+use crate::{
+    ast::expr,
+    codegen,
+    lexer::{
+        location::FileId,
+        source::{Source, SourceFile, SourceMap},
+        token::{PunctKind, TokenKind},
+        tokenize, tokenize_file,
+    },
+    parser::{expr::parse_program, Context as ParserContext, Error as ParseError},
+    run::diagnostics,
+    vm::VM,
+    vm_ctx::{value::Value, FunctionContext},
+};
+use anyhow::Result;
+use rustyline::{error::ReadlineError, Editor};
+use std::{env, path::PathBuf};
+
+const HELP: &str = "\
+:tokens <expr>    show the tokens of <expr>
+:ast <expr>       show the syntax tree of <expr>
+:bytecode <expr>  show the bytecode compiled from <expr>
+:help             show this message
+:quit             exit the REPL
+
+Input is evaluated once it ends with `;;`.";
+
+/// A REPL session. Every input runs on the same VM, so definitions from earlier inputs stay
+/// visible to later ones.
+#[derive(Default)]
+pub struct Repl {
+    vm: VM,
+    map: SourceMap,
+    /// The most recent input.
+    input: FileId,
+    /// Whether the file of the most recent input can be reused for the next one. Inputs that
+    /// define functions are kept, since their code refers to them for error locations.
+    scratch: bool,
+}
+
+/// Starts an interactive session on stdin.
+pub fn run() -> Result<()> {
+    let mut editor = Editor::<()>::new();
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(".eb_history"));
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+
+    let mut repl = Repl::default();
+    let mut input = String::new();
+    loop {
+        let line = match editor.readline(if input.is_empty() { ">> " } else { ".. " }) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                input.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str());
+        }
+
+        if input.is_empty() {
+            let line = line.trim();
+            if line == ":quit" {
+                break;
+            }
+            if let Some(cmd) = line.strip_prefix(':') {
+                let (cmd, arg) = cmd.split_at(cmd.find(' ').unwrap_or(cmd.len()));
+                if let Err(e) = repl.meta(cmd, arg) {
+                    repl.report(&e);
+                }
+                continue;
+            }
+        }
+
+        input.push_str(&line);
+        input.push('\n');
+        if !ends_with_double_semicolon(&input) {
+            continue;
+        }
+        match repl.eval(&input) {
+            Ok(Some(val)) => println!("{}", val),
+            Ok(None) => {}
+            Err(e) if matches!(e.downcast_ref(), Some(ParseError::EOF)) => continue,
+            Err(e) => repl.report(&e),
+        }
+        input.clear();
+    }
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+    Ok(())
+}
+
+impl Repl {
+    /// Evaluates `input` and returns the value of its last expression, unless it is `nil`.
+    pub fn eval(&mut self, input: &str) -> Result<Option<Value>> {
+        // A stray `;;`, such as one closing a function that was already complete, does nothing.
+        if input.trim() == ";;" {
+            return Ok(None);
+        }
+        let id = self.add_input(input);
+        let func = compile(&self.map, id)?;
+        // The functions are defined even if the code fails.
+        self.scratch = func.children.is_empty();
+        let val = self.vm.run(&func)?;
+        Ok(Some(val).filter(|val| !matches!(val, Value::Nil)))
+    }
+
+    fn meta(&mut self, cmd: &str, arg: &str) -> Result<()> {
+        let id = self.add_input(arg.trim());
+        match cmd {
+            "tokens" => {
+                for tok in tokenize_file(&self.map, id) {
+                    println!("{:?}", tok);
+                }
+            }
+            "ast" => println!("{:#?}", parse_input(&self.map, id)?),
+            "bytecode" => dump(&compile(&self.map, id)?, 0),
+            "help" => println!("{}", HELP),
+            _ => anyhow::bail!("unknown command `:{}` (try `:help`)", cmd),
+        }
+        Ok(())
+    }
+
+    /// Registers `input` as the most recent input. It reuses the file of the previous input if
+    /// that one is scratch, and is itself scratch until it defines functions.
+    fn add_input(&mut self, input: &str) -> FileId {
+        let file = Source::File(SourceFile::from_string(
+            "<repl>".to_owned(),
+            input.to_owned(),
+        ));
+        if self.scratch {
+            self.map.replace(self.input, file);
+        } else {
+            self.input = self.map.add(file);
+        }
+        self.scratch = true;
+        self.input
+    }
+
+    /// Prints an error raised while handling the most recent input.
+    fn report(&self, err: &anyhow::Error) {
+        for diag in diagnostics(&self.map, self.input, err) {
+            diag.emit(&self.map);
+        }
+    }
+}
+
+fn parse_input(map: &SourceMap, id: FileId) -> Result<expr::Node> {
+    let mut ctx = ParserContext::new(tokenize_file(map, id));
+    let node = parse_program(&mut ctx)?;
+    ctx.finish()?;
+    Ok(node)
+}
+
+fn compile(map: &SourceMap, id: FileId) -> Result<FunctionContext> {
+    let node = parse_input(map, id)?;
+    let mut func = FunctionContext::default();
+    codegen::expr::visit(&mut func, &node)?;
+    Ok(func)
+}
+
+fn ends_with_double_semicolon(input: &str) -> bool {
+    let source = Source::String(input.to_owned());
+    let last = tokenize(&source).last();
+    matches!(
+        last.as_ref().map(|tok| tok.kind()),
+        Some(TokenKind::Punct(PunctKind::DoubleSemicolon))
+    )
+}
+
+fn dump(func: &FunctionContext, depth: usize) {
+    let indent = "  ".repeat(depth);
+    println!(
+        "{}func {}({}):",
+        indent,
+        func.name,
+        func.param_names.join(", ")
+    );
+    for (i, inst) in func.code.0.iter().enumerate() {
+        println!("{}  {:04} {:?}", indent, i, inst);
+    }
+    for child in &func.children {
+        dump(child, depth + 1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn repl1() {
+        let mut repl = Repl::default();
+        assert!(repl.eval("func double(x): x * 2 ;;").unwrap().is_none());
+        assert!(matches!(
+            repl.eval("double(21) ;;").unwrap(),
+            Some(Value::Int(42))
+        ));
+        assert!(matches!(
+            repl.eval("func quad(x): double(double(x)) ;;\nquad(3) ;;")
+                .unwrap(),
+            Some(Value::Int(12))
+        ));
+        assert!(repl.vm.stack.is_empty());
+
+        let err = repl.eval("double(1) / 0 ;;").unwrap_err();
+        assert_eq!(err.to_string(), "division by zero");
+        assert!(matches!(
+            repl.eval("double(1) ;;").unwrap(),
+            Some(Value::Int(2))
+        ));
+        assert!(repl.vm.stack.is_empty());
+    }
+
+    #[test]
+    fn repl2() {
+        let mut repl = Repl::default();
+        let err = repl.eval("func f(x):\n").unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(ParseError::EOF)));
+        assert!(!ends_with_double_semicolon("f(1)\n"));
+        assert!(ends_with_double_semicolon("f(1)\n;;\n"));
+
+        assert!(repl.eval("func f(x):\n x * 2 ;;\n").unwrap().is_none());
+        assert!(repl.eval(";;\n").unwrap().is_none());
+        assert!(matches!(
+            repl.eval("f(2) ;;\n").unwrap(),
+            Some(Value::Int(4))
+        ));
+    }
+
+    #[test]
+    fn repl3() {
+        let mut repl = Repl::default();
+        let err = repl
+            .eval("1 ;;")
+            .and(repl.eval("f(\n 1 2) ;;"))
+            .unwrap_err();
+        let diag = &diagnostics(&repl.map, repl.input, &err)[0];
+        assert!(diag
+            .render(&repl.map, false)
+            .starts_with("error: expected `)` after argument\n --> <repl>:2:4\n"));
+    }
+
+    #[test]
+    fn repl4() {
+        let mut repl = Repl::default();
+        repl.eval("func f(x): x / 0 ;;").unwrap();
+        for _ in 0..10 {
+            repl.eval("1 + 1 ;;").unwrap();
+            repl.eval("1 +").unwrap_err();
+            repl.meta("tokens", "1").unwrap();
+        }
+        // Only the definition of `f` and a scratch file are kept.
+        assert_eq!(repl.input, FileId(1));
+
+        // The definition of `f` is still there to point at.
+        let err = repl.eval("\n f(1) ;;").unwrap_err();
+        let diag = &diagnostics(&repl.map, repl.input, &err)[0];
+        assert!(diag
+            .render(&repl.map, false)
+            .starts_with("error: division by zero\n --> <repl>:1:12\n"));
+        assert_eq!(diag.notes()[1], "at <top-level> (<repl>:2:2)");
+    }
+}
@@ -0,0 +1,288 @@
+use crate::{
+    codegen,
+    diagnostics::Diagnostic,
+    lexer::{
+        location::{FileId, Span},
+        source::{Source, SourceFile, SourceMap},
+        tokenize_file,
+    },
+    parser::{self, expr::parse_program, Context as ParserContext},
+    vm::{RuntimeError, VM},
+    vm_ctx::{inst::Inst, value::Value, FunctionContext},
+};
+use anyhow::Result;
+use std::process::ExitCode;
+
+/// Runs the script at `path` and prints its value, unless it is `nil`. Errors are reported on
+/// stderr.
+pub fn run(path: &str, args: &[String]) -> ExitCode {
+    let mut map = SourceMap::default();
+    let file = match SourceFile::new(path.to_owned()) {
+        Ok(file) => file,
+        Err(e) => {
+            Diagnostic::error(format!("cannot read `{}`: {}", path, e)).emit(&map);
+            return ExitCode::FAILURE;
+        }
+    };
+    let id = map.add(Source::File(file));
+    match eval(&map, id, args) {
+        Ok(val) => {
+            if let Some(val) = val {
+                println!("{}", val);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            for diag in diagnostics(&map, id, &e) {
+                diag.emit(&map);
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Parses, compiles and executes the file `id`, and returns the value of its last expression
+/// unless it is `nil`. If the script defines a top-level `main` function, it is called with `args`
+/// after the top-level code has run, and its result is returned instead.
+pub fn eval(map: &SourceMap, id: FileId, args: &[String]) -> Result<Option<Value>> {
+    let mut ctx = ParserContext::new(tokenize_file(map, id));
+    let node = parse_program(&mut ctx).map_err(|e| ctx.report(e)).ok();
+    ctx.finish()?;
+    let node = node.expect("parse errors are reported");
+
+    let mut func = FunctionContext::default();
+    codegen::expr::visit(&mut func, &node)?;
+    if func.children.iter().any(|child| child.name == "main") {
+        call_main(&mut func, args, node.span());
+    } else if !args.is_empty() {
+        anyhow::bail!("script arguments given, but the script does not define `main`");
+    }
+
+    let val = VM::default().run(&func)?;
+    Ok(Some(val).filter(|val| !matches!(val, Value::Nil)))
+}
+
+/// Converts an error raised while evaluating the file `id` into diagnostics, one for each syntax
+/// error if there are several.
+pub fn diagnostics(map: &SourceMap, id: FileId, err: &anyhow::Error) -> Vec<Diagnostic> {
+    match err.downcast_ref::<parser::Errors>() {
+        Some(errs) => errs.0.iter().map(|e| diagnostic(map, id, e)).collect(),
+        None => vec![diagnostic(map, id, err)],
+    }
+}
+
+/// Converts a single error into a diagnostic. An unexpected end of input points just past the end
+/// of the file.
+fn diagnostic(map: &SourceMap, id: FileId, err: &anyhow::Error) -> Diagnostic {
+    match parser::diagnostic(err) {
+        Some(diag) if diag.span().is_none() => {
+            let end = map.get(id).body().len() as u32;
+            diag.with_span(Span::new(id, end, end))
+        }
+        Some(diag) => diag,
+        None => match (codegen::diagnostic(err), err.downcast_ref::<RuntimeError>()) {
+            (Some(diag), _) => diag,
+            (None, Some(err)) => runtime_diagnostic(map, err),
+            (None, None) => Diagnostic::error(format!("{:#}", err)),
+        },
+    }
+}
+
+/// Points a runtime error at the failing expression, with a note per running call, innermost
+/// first. Runs of the same call, as in deep recursion, are collapsed into one note.
+fn runtime_diagnostic(map: &SourceMap, err: &RuntimeError) -> Diagnostic {
+    let mut diag = Diagnostic::error(err.message());
+    if let Some(span) = err.trace().first().and_then(|frame| frame.span) {
+        diag = diag.with_span(span);
+    }
+    let mut frames = err.trace().iter().peekable();
+    while let Some(frame) = frames.next() {
+        let name = match frame.func.as_str() {
+            "" => "<top-level>",
+            name => name,
+        };
+        diag = diag.with_note(match frame.span {
+            Some(span) => format!("at {} ({})", name, map.location(span)),
+            None => format!("at {}", name),
+        });
+        let mut repeated = 0;
+        while frames.next_if_eq(&frame).is_some() {
+            repeated += 1;
+        }
+        if repeated > 0 {
+            diag = diag.with_note(format!("... repeated {} more time(s)", repeated));
+        }
+    }
+    diag
+}
+
+/// Emits `main(args...)`, attributed to `span` in the line table. Arguments that look like
+/// integers are passed as `Int`, the rest as `String`.
+fn call_main(func: &mut FunctionContext, args: &[String], span: Span) {
+    func.push_at(Inst::Pop, span);
+    for arg in args {
+        let inst = match arg.parse() {
+            Ok(i) => Inst::PushInt(i),
+            Err(_) => Inst::PushStr(arg.to_owned()),
+        };
+        func.push_at(inst, span);
+    }
+    func.push_at(Inst::Get("main".to_owned()), span);
+    func.push_at(Inst::Call(args.len()), span);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eval_str(s: &str, args: &[&str]) -> Result<Option<Value>> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let mut map = SourceMap::default();
+        let id = map.add(Source::String(s.to_string()));
+        eval(&map, id, &args)
+    }
+
+    #[test]
+    fn eval1() {
+        let val = eval_str(
+            r#"
+            func f(x):
+                if x == 1:
+                    return 1 ;;
+                x * f(x - 1) ;;
+            f(5) ;;"#,
+            &[],
+        );
+        assert!(matches!(val.unwrap(), Some(Value::Int(120))));
+    }
+
+    #[test]
+    fn eval2() {
+        let val = eval_str(r#"func main(x, y): x * y ;; ;;"#, &["6", "7"]);
+        assert!(matches!(val.unwrap(), Some(Value::Int(42))));
+    }
+
+    #[test]
+    fn eval3() {
+        assert!(eval_str(r#"f(1 ;;"#, &[]).is_err());
+        assert!(eval_str(r#"1 2"#, &[]).is_err());
+        assert!(eval_str(r#"1 ;;"#, &["1"]).is_err());
+    }
+
+    #[test]
+    fn eval4() {
+        let render = |s: &str| {
+            let mut map = SourceMap::default();
+            let id = map.add(Source::String(s.to_string()));
+            let err = eval(&map, id, &[]).unwrap_err();
+            diagnostics(&map, id, &err)
+                .iter()
+                .map(|diag| diag.render(&map, false))
+                .collect::<String>()
+        };
+        assert_eq!(
+            render("f(1,\n  2 3) ;;"),
+            "\
+error: expected `)` after argument
+ --> <string>:2:5
+  |
+2 |   2 3) ;;
+  |     ^
+"
+        );
+        assert!(render("f(1,\n  2")
+            .starts_with("error: unexpected end of file after argument\n --> <string>:2:4\n"));
+    }
+
+    #[test]
+    fn eval5() {
+        let mut map = SourceMap::default();
+        let id = map.add(Source::String("f(1 2) ;\ng(,) ;\n3 ;;".to_string()));
+        let err = eval(&map, id, &[]).unwrap_err();
+        let diags = diagnostics(&map, id, &err);
+        let locs: Vec<_> = diags
+            .iter()
+            .map(|diag| map.location(diag.span().unwrap()).to_string())
+            .collect();
+        assert_eq!(locs, ["<string>:1:5", "<string>:2:3"]);
+    }
+
+    #[test]
+    fn eval6() {
+        let mut map = SourceMap::default();
+        let file = SourceFile::from_string(
+            "script.eb".to_owned(),
+            "func fact(n):\n    if n == 0: return nope ;;\n    n * fact(n - 1) ;;\nfact(1) ;;"
+                .to_owned(),
+        );
+        let id = map.add(Source::File(file));
+        let err = eval(&map, id, &[]).unwrap_err();
+        assert_eq!(
+            diagnostics(&map, id, &err)[0].render(&map, false),
+            "\
+error: undefined variable `nope`
+ --> script.eb:2:23
+  |
+2 |     if n == 0: return nope ;;
+  |                       ^^^^
+  = note: at fact (script.eb:2:23)
+  = note: at fact (script.eb:3:9)
+  = note: at <top-level> (script.eb:4:1)
+"
+        );
+    }
+
+    #[test]
+    fn eval7() {
+        let mut map = SourceMap::default();
+        let file = SourceFile::from_string(
+            "script.eb".to_owned(),
+            "func f(n): f(n + 1) ;;\nf(0) ;;".to_owned(),
+        );
+        let id = map.add(Source::File(file));
+        let err = eval(&map, id, &[]).unwrap_err();
+        assert_eq!(
+            diagnostics(&map, id, &err)[0].render(&map, false),
+            "\
+error: maximum call depth of 1000 exceeded
+ --> script.eb:1:12
+  |
+1 | func f(n): f(n + 1) ;;
+  |            ^^^^^^^^
+  = note: at f (script.eb:1:12)
+  = note: ... repeated 998 more time(s)
+  = note: at <top-level> (script.eb:2:1)
+"
+        );
+    }
+
+    #[test]
+    fn eval8() {
+        let mut map = SourceMap::default();
+        let file = SourceFile::from_string(
+            "script.eb".to_owned(),
+            "func f(x):\n    if x: break ;;\n    x ;;\ncontinue ;;".to_owned(),
+        );
+        let id = map.add(Source::File(file));
+        let err = eval(&map, id, &[]).unwrap_err();
+        assert_eq!(
+            diagnostics(&map, id, &err)[0].render(&map, false),
+            "\
+error: `break` outside of a loop
+ --> script.eb:2:11
+  |
+2 |     if x: break ;;
+  |           ^^^^^
+"
+        );
+        let file = SourceFile::from_string("script.eb".to_owned(), "1 ;\ncontinue ;;".to_owned());
+        let id = map.add(Source::File(file));
+        let err = eval(&map, id, &[]).unwrap_err();
+        let diag = &diagnostics(&map, id, &err)[0];
+        assert_eq!(diag.message(), "`continue` outside of a loop");
+        assert_eq!(
+            map.location(diag.span().unwrap()).to_string(),
+            "script.eb:2:1"
+        );
+    }
+}
@@ -1,44 +1,81 @@
 use super::function;
-use lexer::location::Location;
+use lexer::location::Span;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Node {
     kind: Kind,
-    loc: Location,
+    span: Span,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Kind {
     Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
     Ident(String),
     Function(Box<function::Node>),
     BinOp(BinOpKind, Box<Node>, Box<Node>),
+    UnaryOp(UnaryOpKind, Box<Node>),
     Call(Box<Node>, Vec<Node>),
+    Index(Box<Node>, Box<Node>),
+    Field(Box<Node>, String),
+    List(Vec<Node>),
     If(Box<Node>, Box<Node>, Option<Box<Node>>),
+    While(Box<Node>, Box<Node>),
+    /// `for x in iterable: body ;;`
+    For(String, Box<Node>, Box<Node>),
+    /// Leaves the innermost loop, which evaluates to the value if one is given and to `nil`
+    /// otherwise.
+    Break(Option<Box<Node>>),
+    Continue,
     Return(Box<Node>),
+    Let(String, Box<Node>),
+    Assign(String, Box<Node>),
+    /// `x += 1` and the like.
+    CompoundAssign(BinOpKind, String, Box<Node>),
     Exprs(Vec<Node>),
+    /// Source the parser skipped over after a syntax error.
+    Error,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BinOpKind {
     Add,
     Sub,
     Mul,
     Div,
+    Pow,
     Eq,
     Neq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOpKind {
+    Not,
+    Neg,
 }
 
 impl Node {
-    pub fn new(kind: Kind, loc: Location) -> Self {
-        Self { kind, loc }
+    pub fn new(kind: Kind, span: Span) -> Self {
+        Self { kind, span }
     }
 
     pub fn kind(&self) -> &Kind {
         &self.kind
     }
 
-    pub fn loc(&self) -> &Location {
-        &self.loc
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    pub fn set_span(&mut self, span: Span) {
+        self.span = span;
     }
 }
@@ -1,20 +1,28 @@
 use super::expr;
+use lexer::location::Span;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Node {
     name: String,
     params: Vec<Param>,
     body: expr::Node,
+    span: Span,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Param {
     name: String,
+    span: Span,
 }
 
 impl Node {
-    pub fn new(name: String, params: Vec<Param>, body: expr::Node) -> Self {
-        Self { name, params, body }
+    pub fn new(name: String, params: Vec<Param>, body: expr::Node, span: Span) -> Self {
+        Self {
+            name,
+            params,
+            body,
+            span,
+        }
     }
 
     pub fn name(&self) -> &String {
@@ -28,14 +36,22 @@ impl Node {
     pub fn body(&self) -> &expr::Node {
         &self.body
     }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
 }
 
 impl Param {
-    pub fn new(name: String) -> Self {
-        Self { name }
+    pub fn new(name: String, span: Span) -> Self {
+        Self { name, span }
     }
 
     pub fn name(&self) -> &String {
         &self.name
     }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
 }
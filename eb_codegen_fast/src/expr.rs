@@ -1,62 +1,293 @@
-use super::function;
+use super::{
+    function,
+    resolve::{self, Scope, Var},
+    Error,
+};
 use anyhow::Result;
-use ast::expr as ast_expr;
-use vm_ctx::{inst::Inst, FunctionContext as Context};
+use ast::{expr as ast_expr, function as ast_func};
+use lexer::location::Span;
+use std::{
+    mem,
+    ops::{Deref, DerefMut},
+};
+use vm_ctx::{inst::Inst, FunctionContext};
 
-pub fn visit(ctx: &mut Context, expr: &ast_expr::Node) -> Result<()> {
+/// The function being compiled, along with what is needed to resolve variables and lower loops.
+struct Context<'a> {
+    func: &'a mut FunctionContext,
+    /// Variables of the function. Top-level code has none, so all of its variables are global.
+    scope: &'a Scope,
+    /// Whether this is the body of a function, whose prologue creates its nested functions.
+    /// Functions defined in top-level code become globals instead.
+    in_function: bool,
+    /// Enclosing loops, innermost last.
+    loops: Vec<Loop>,
+    /// Number of values pushed by enclosing expressions that are still waiting for their
+    /// operator. `break` and `continue` pop them before jumping out.
+    depth: u32,
+    /// Span of the innermost expression being compiled, which instructions are attributed to.
+    span: Span,
+}
+
+struct Loop {
+    /// Where `continue` jumps to.
+    start: usize,
+    /// `depth` outside the loop.
+    depth: u32,
+    /// `depth` in the loop body, which is deeper than outside by the iteration state of `for`.
+    body_depth: u32,
+    /// Jumps emitted by `break`, to be patched to the end of the loop.
+    breaks: Vec<usize>,
+}
+
+impl Context<'_> {
+    /// Pushes `inst` and records it in the line table.
+    fn push(&mut self, inst: Inst) {
+        let span = self.span;
+        self.func.push_at(inst, span)
+    }
+}
+
+impl Deref for Context<'_> {
+    type Target = FunctionContext;
+
+    fn deref(&self) -> &FunctionContext {
+        self.func
+    }
+}
+
+impl DerefMut for Context<'_> {
+    fn deref_mut(&mut self) -> &mut FunctionContext {
+        self.func
+    }
+}
+
+pub fn visit(func: &mut FunctionContext, expr: &ast_expr::Node) -> Result<()> {
+    let mut ctx = Context {
+        func,
+        scope: &Scope::default(),
+        in_function: false,
+        loops: vec![],
+        depth: 0,
+        span: expr.span(),
+    };
+    visit_expr(&mut ctx, expr)
+}
+
+/// Compiles the body of `node` into `func`, with the variables in `scope`. It starts by moving
+/// captured parameters to their upvalues, binding the function to its own name if it refers to
+/// itself, and creating closures for the nested functions, so that these can be called before
+/// their definition.
+pub fn visit_body(func: &mut FunctionContext, scope: &Scope, node: &ast_func::Node) -> Result<()> {
+    let mut ctx = Context {
+        func,
+        scope,
+        in_function: true,
+        loops: vec![],
+        depth: 0,
+        span: node.span(),
+    };
+    for param in node.params() {
+        if let (Some(slot), Var::Upvalue(idx)) =
+            (scope.slot(param.name()), scope.lookup(param.name()))
+        {
+            ctx.push(Inst::GetLocal(slot));
+            ctx.push(Inst::SetUpvalue(idx));
+            ctx.push(Inst::Pop);
+        }
+    }
+    if let Some(name) = scope.itself() {
+        ctx.push(Inst::GetCallee);
+        define(&mut ctx, name);
+        ctx.push(Inst::Pop);
+    }
+    for (i, nested) in resolve::nested_functions(node.body())
+        .into_iter()
+        .enumerate()
+    {
+        let mut child = FunctionContext::default();
+        function::visit_nested(&mut child, nested, scope)?;
+        ctx.add_child(child);
+        ctx.push(Inst::MakeClosure(i));
+        define(&mut ctx, nested.name());
+        ctx.push(Inst::Pop);
+    }
+    visit_expr(&mut ctx, node.body())
+}
+
+fn visit_expr(ctx: &mut Context, expr: &ast_expr::Node) -> Result<()> {
+    let outer = mem::replace(&mut ctx.span, expr.span());
+    let result = visit_kind(ctx, expr);
+    ctx.span = outer;
+    result
+}
+
+fn visit_kind(ctx: &mut Context, expr: &ast_expr::Node) -> Result<()> {
     match expr.kind() {
         ast_expr::Kind::Int(i) => {
             ctx.push(Inst::PushInt(*i)); // TODO
         }
+        ast_expr::Kind::Float(f) => {
+            ctx.push(Inst::PushFloat(*f));
+        }
+        ast_expr::Kind::Bool(b) => {
+            ctx.push(Inst::PushBool(*b));
+        }
+        ast_expr::Kind::Str(s) => {
+            ctx.push(Inst::PushStr(s.to_owned()));
+        }
         ast_expr::Kind::Ident(ident) => {
-            ctx.push(Inst::Get(ident.to_owned()));
+            let inst = match ctx.scope.lookup(ident) {
+                Var::Local(slot) => Inst::GetLocal(slot),
+                Var::Upvalue(idx) => Inst::GetUpvalue(idx),
+                Var::Global => Inst::Get(ident.to_owned()),
+            };
+            ctx.push(inst);
         }
         ast_expr::Kind::BinOp(op, lhs, rhs) => visit_binop(ctx, op, lhs, rhs)?,
+        ast_expr::Kind::UnaryOp(op, operand) => {
+            visit_expr(ctx, operand)?;
+            ctx.push(match op {
+                ast_expr::UnaryOpKind::Not => Inst::Not,
+                ast_expr::UnaryOpKind::Neg => Inst::Neg,
+            });
+        }
         ast_expr::Kind::Function(func) => {
-            let mut ctx_ = Context::default();
-            function::visit(&mut ctx_, func)?;
-            ctx.add_child(ctx_);
+            if !ctx.in_function {
+                let mut ctx_ = FunctionContext::default();
+                function::visit(&mut ctx_, func)?;
+                ctx.add_child(ctx_);
+            }
+            ctx.push(Inst::PushNil);
         }
         ast_expr::Kind::Call(callee, args) => visit_call(ctx, callee, args)?,
+        ast_expr::Kind::Index(base, index) => {
+            visit_expr(ctx, base)?;
+            ctx.depth += 1;
+            visit_expr(ctx, index)?;
+            ctx.depth -= 1;
+            ctx.push(Inst::Index);
+        }
+        ast_expr::Kind::Field(base, field) => {
+            visit_expr(ctx, base)?;
+            ctx.push(Inst::GetField(field.to_owned()));
+        }
+        ast_expr::Kind::List(elems) => {
+            for elem in elems {
+                visit_expr(ctx, elem)?;
+                ctx.depth += 1;
+            }
+            ctx.depth -= elems.len() as u32;
+            ctx.push(Inst::MakeList(elems.len()));
+        }
         ast_expr::Kind::If(cond, then_, else_) => visit_if(ctx, cond, then_, else_)?,
+        ast_expr::Kind::While(cond, body) => visit_while(ctx, cond, body)?,
+        ast_expr::Kind::For(var, iterable, body) => visit_for(ctx, var, iterable, body)?,
+        ast_expr::Kind::Break(val) => visit_break(ctx, val)?,
+        ast_expr::Kind::Continue => visit_continue(ctx)?,
         ast_expr::Kind::Return(val) => visit_ret(ctx, val)?,
+        ast_expr::Kind::Let(name, val) => {
+            visit_expr(ctx, val)?;
+            define(ctx, name);
+        }
+        ast_expr::Kind::Assign(name, val) => {
+            visit_expr(ctx, val)?;
+            set(ctx, name);
+        }
+        ast_expr::Kind::CompoundAssign(op, name, val) => {
+            let var = ast_expr::Node::new(ast_expr::Kind::Ident(name.to_owned()), expr.span());
+            visit_binop(ctx, op, &var, val)?;
+            set(ctx, name);
+        }
         ast_expr::Kind::Exprs(exprs) => {
-            for expr in exprs {
-                visit(ctx, expr)?;
+            // Only the value of the last expression is kept.
+            for (i, expr) in exprs.iter().enumerate() {
+                if i > 0 {
+                    ctx.push(Inst::Pop);
+                }
+                visit_expr(ctx, expr)?;
+            }
+            if exprs.is_empty() {
+                ctx.push(Inst::PushNil);
             }
         }
+        ast_expr::Kind::Error => return Err(Error::SyntaxError(ctx.span).into()),
     }
     Ok(())
 }
 
+/// Emits the binding of a new variable `name` to the top of the stack.
+fn define(ctx: &mut Context, name: &str) {
+    let inst = match ctx.scope.lookup(name) {
+        Var::Local(slot) => Inst::SetLocal(slot),
+        Var::Upvalue(idx) => Inst::SetUpvalue(idx),
+        Var::Global => Inst::Define(name.to_owned()),
+    };
+    ctx.push(inst);
+}
+
+/// Emits the assignment of the top of the stack to the variable `name`.
+fn set(ctx: &mut Context, name: &str) {
+    let inst = match ctx.scope.lookup(name) {
+        Var::Local(slot) => Inst::SetLocal(slot),
+        Var::Upvalue(idx) => Inst::SetUpvalue(idx),
+        Var::Global => Inst::Set(name.to_owned()),
+    };
+    ctx.push(inst);
+}
+
 fn visit_binop(
     ctx: &mut Context,
     op: &ast_expr::BinOpKind,
     lhs: &ast_expr::Node,
     rhs: &ast_expr::Node,
 ) -> Result<()> {
-    visit(ctx, lhs)?;
-    visit(ctx, rhs)?;
+    visit_expr(ctx, lhs)?;
+    if let ast_expr::BinOpKind::And | ast_expr::BinOpKind::Or = op {
+        return visit_logical(ctx, op, rhs);
+    }
+    ctx.depth += 1;
+    visit_expr(ctx, rhs)?;
+    ctx.depth -= 1;
     match op {
+        ast_expr::BinOpKind::Add => ctx.push(Inst::Add),
         ast_expr::BinOpKind::Sub => ctx.push(Inst::Sub),
         ast_expr::BinOpKind::Mul => ctx.push(Inst::Mul),
+        ast_expr::BinOpKind::Div => ctx.push(Inst::Div),
+        ast_expr::BinOpKind::Pow => ctx.push(Inst::Pow),
         ast_expr::BinOpKind::Eq => ctx.push(Inst::Eq),
-        _ => todo!(),
+        ast_expr::BinOpKind::Neq => ctx.push(Inst::Neq),
+        ast_expr::BinOpKind::Lt => ctx.push(Inst::Lt),
+        ast_expr::BinOpKind::Le => ctx.push(Inst::Le),
+        ast_expr::BinOpKind::Gt => ctx.push(Inst::Gt),
+        ast_expr::BinOpKind::Ge => ctx.push(Inst::Ge),
+        ast_expr::BinOpKind::And | ast_expr::BinOpKind::Or => unreachable!(),
     }
     Ok(())
 }
 
+/// Emits the rest of `lhs && rhs` or `lhs || rhs` once `lhs` is on the stack. `rhs` is skipped
+/// when `lhs` decides the result, which is then `lhs` itself.
+fn visit_logical(ctx: &mut Context, op: &ast_expr::BinOpKind, rhs: &ast_expr::Node) -> Result<()> {
+    let cur = ctx.code.len();
+    ctx.push(match op {
+        ast_expr::BinOpKind::And => Inst::JmpIfFalseOrPop(0),
+        _ => Inst::JmpIfTrueOrPop(0),
+    });
+    visit_expr(ctx, rhs)?;
+    let merge = ctx.code.len();
+    patch(ctx, cur, merge);
+    Ok(())
+}
+
 fn visit_call(ctx: &mut Context, callee: &ast_expr::Node, args: &[ast_expr::Node]) -> Result<()> {
     for arg in args {
-        visit(ctx, arg)?;
+        visit_expr(ctx, arg)?;
+        ctx.depth += 1;
     }
-    match callee.kind() {
-        ast_expr::Kind::Ident(name) => {
-            ctx.push(Inst::Get(name.to_owned()));
-        }
-        _ => todo!(),
-    }
-    ctx.push(Inst::Call);
+    visit_expr(ctx, callee)?;
+    ctx.depth -= args.len() as u32;
+    ctx.push(Inst::Call(args.len()));
     Ok(())
 }
 
@@ -66,21 +297,146 @@ fn visit_if(
     then_: &ast_expr::Node,
     else_: &Option<Box<ast_expr::Node>>,
 ) -> Result<()> {
-    assert!(else_.is_none());
-    visit(ctx, cond)?;
-    let cur = ctx.code.len() as i32;
+    visit_expr(ctx, cond)?;
+    let jne = ctx.code.len();
     ctx.push(Inst::Jne(0));
-    visit(ctx, then_)?;
-    let merge = ctx.code.len() as i32;
-    match ctx.code.get_mut(cur as usize).unwrap() {
-        Inst::Jne(ref mut offset) => *offset = merge - cur,
-        _ => panic!(),
+    visit_expr(ctx, then_)?;
+    let jmp = ctx.code.len();
+    ctx.push(Inst::Jmp(0));
+    let else_start = ctx.code.len();
+    match else_ {
+        Some(else_) => visit_expr(ctx, else_)?,
+        None => ctx.push(Inst::PushNil),
+    }
+    let merge = ctx.code.len();
+    patch(ctx, jne, else_start);
+    patch(ctx, jmp, merge);
+    Ok(())
+}
+
+/// Emits `while cond: body ;;`. The loop evaluates to `nil` unless `break` gives it a value.
+fn visit_while(ctx: &mut Context, cond: &ast_expr::Node, body: &ast_expr::Node) -> Result<()> {
+    let start = ctx.code.len();
+    visit_expr(ctx, cond)?;
+    let jne = ctx.code.len();
+    ctx.push(Inst::Jne(0));
+    let depth = ctx.depth;
+    visit_loop_body(ctx, start, depth, body)?;
+    let exit = ctx.code.len();
+    patch(ctx, jne, exit);
+    finish_loop(ctx);
+    Ok(())
+}
+
+/// Emits `for var in iterable: body ;;`. The iterable and the index of its next element stay on
+/// the stack while the loop runs.
+fn visit_for(
+    ctx: &mut Context,
+    var: &str,
+    iterable: &ast_expr::Node,
+    body: &ast_expr::Node,
+) -> Result<()> {
+    visit_expr(ctx, iterable)?;
+    ctx.push(Inst::Iter);
+    let start = ctx.code.len();
+    ctx.push(Inst::IterNext(0));
+    define(ctx, var);
+    ctx.push(Inst::Pop);
+    let depth = ctx.depth;
+    ctx.depth += 2;
+    visit_loop_body(ctx, start, depth, body)?;
+    ctx.depth -= 2;
+    let exit = ctx.code.len();
+    patch(ctx, start, exit);
+    finish_loop(ctx);
+    Ok(())
+}
+
+/// Emits the body of a loop whose next iteration starts at `start`, and the jump back to it.
+/// `depth` is the depth outside the loop.
+fn visit_loop_body(
+    ctx: &mut Context,
+    start: usize,
+    depth: u32,
+    body: &ast_expr::Node,
+) -> Result<()> {
+    let body_depth = ctx.depth;
+    ctx.loops.push(Loop {
+        start,
+        depth,
+        body_depth,
+        breaks: vec![],
+    });
+    visit_expr(ctx, body)?;
+    ctx.push(Inst::Pop);
+    jump_back(ctx, start);
+    Ok(())
+}
+
+/// Emits the value of a loop that ran to completion, and patches its `break`s to jump past it.
+fn finish_loop(ctx: &mut Context) {
+    ctx.push(Inst::PushNil);
+    let end = ctx.code.len();
+    let loop_ = ctx.loops.pop().unwrap();
+    for at in loop_.breaks {
+        patch(ctx, at, end);
+    }
+}
+
+fn visit_break(ctx: &mut Context, val: &Option<Box<ast_expr::Node>>) -> Result<()> {
+    let depth = match ctx.loops.last() {
+        Some(loop_) => loop_.depth,
+        None => return Err(Error::BreakOutsideLoop(ctx.span).into()),
+    };
+    let pending = ctx.depth;
+    for _ in depth..pending {
+        ctx.push(Inst::Pop);
+    }
+    ctx.depth = depth;
+    match val {
+        Some(val) => visit_expr(ctx, val)?,
+        None => ctx.push(Inst::PushNil),
     }
+    ctx.depth = pending;
+    let at = ctx.code.len();
+    ctx.push(Inst::Jmp(0));
+    ctx.loops.last_mut().unwrap().breaks.push(at);
     Ok(())
 }
 
+fn visit_continue(ctx: &mut Context) -> Result<()> {
+    let (start, depth) = match ctx.loops.last() {
+        Some(loop_) => (loop_.start, loop_.body_depth),
+        None => return Err(Error::ContinueOutsideLoop(ctx.span).into()),
+    };
+    for _ in depth..ctx.depth {
+        ctx.push(Inst::Pop);
+    }
+    jump_back(ctx, start);
+    Ok(())
+}
+
+/// Emits a jump to the earlier instruction `target`.
+fn jump_back(ctx: &mut Context, target: usize) {
+    let offset = target as i32 - ctx.code.len() as i32;
+    ctx.push(Inst::Jmp(offset));
+}
+
+/// Makes the jump emitted at `at` land on `target`.
+fn patch(ctx: &mut Context, at: usize, target: usize) {
+    let offset = target as i32 - at as i32;
+    match ctx.code.get_mut(at).unwrap() {
+        Inst::Jmp(ref mut o)
+        | Inst::Jne(ref mut o)
+        | Inst::JmpIfFalseOrPop(ref mut o)
+        | Inst::JmpIfTrueOrPop(ref mut o)
+        | Inst::IterNext(ref mut o) => *o = offset,
+        _ => panic!(),
+    }
+}
+
 fn visit_ret(ctx: &mut Context, val: &ast_expr::Node) -> Result<()> {
-    visit(ctx, val)?;
+    visit_expr(ctx, val)?;
     ctx.push(Inst::Ret);
     Ok(())
 }
@@ -1,58 +1,91 @@
-use super::expr;
+use super::{expr, resolve};
 use anyhow::Result;
 use ast::function as func;
 use vm_ctx::FunctionContext as Context;
 
 pub fn visit(ctx: &mut Context, func: &func::Node) -> Result<()> {
+    visit_nested(ctx, func, &resolve::Scope::default())
+}
+
+/// Compiles `func`, which is defined in the function whose variables are `parent`.
+pub fn visit_nested(ctx: &mut Context, func: &func::Node, parent: &resolve::Scope) -> Result<()> {
+    let scope = resolve::resolve(func, parent)?;
     ctx.name = func.name().to_owned();
     ctx.param_names = func.params().iter().map(|p| p.name().to_owned()).collect();
-    expr::visit(ctx, func.body())?;
+    ctx.locals = scope.names().to_vec();
+    ctx.captures = scope.captures().to_vec();
+    ctx.captured_locals = scope.captured_locals();
+    expr::visit_body(ctx, &scope, func)?;
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
-    extern crate eb_lexer as lexer;
     extern crate eb_parser as parser;
     extern crate insta;
     use super::*;
     use lexer::{source::Source, tokenize};
     use parser::{function::parse, Context as ParserContext};
 
-    #[test]
-    fn codegen1() {
-        let source = Source::String(r#"func f(): ;;"#.to_string());
+    fn compile(src: &str) -> Context {
+        let source = Source::String(src.to_string());
         let mut ctx = ParserContext::new(tokenize(&source));
         let node = parse(&mut ctx).expect("fail to parse");
         let mut ctx = Context::default();
         visit(&mut ctx, &node).unwrap();
-        insta::assert_debug_snapshot!(ctx);
+        ctx
+    }
+
+    #[test]
+    fn codegen1() {
+        insta::assert_debug_snapshot!(compile(r#"func f(): ;;"#));
     }
 
     #[test]
     fn codegen2() {
-        let source = Source::String(r#"func f(x): x;;"#.to_string());
-        let mut ctx = ParserContext::new(tokenize(&source));
-        let node = parse(&mut ctx).expect("fail to parse");
-        let mut ctx = Context::default();
-        visit(&mut ctx, &node).unwrap();
-        insta::assert_debug_snapshot!(ctx);
+        insta::assert_debug_snapshot!(compile(r#"func f(x): x;;"#));
     }
 
     #[test]
     fn codegen3() {
-        let source = Source::String(
+        insta::assert_debug_snapshot!(compile(
             r#"
             func f(x): 
                 if x == 1:
                     return 1 ;;
                 x * f(x - 1) ;;"#
-                .to_string(),
-        );
-        let mut ctx = ParserContext::new(tokenize(&source));
-        let node = parse(&mut ctx).expect("fail to parse");
-        let mut ctx = Context::default();
-        visit(&mut ctx, &node).unwrap();
-        insta::assert_debug_snapshot!(ctx);
+        ));
+    }
+
+    #[test]
+    fn codegen4() {
+        insta::assert_debug_snapshot!(compile(r#"func f(x): 0 < x && x < 10 || !x ;;"#));
+    }
+
+    #[test]
+    fn codegen5() {
+        insta::assert_debug_snapshot!(compile(r#"func f(g): g(1)(2)[0].x ;;"#));
+    }
+
+    #[test]
+    fn codegen6() {
+        insta::assert_debug_snapshot!(compile(
+            r#"
+            func f(xs):
+                for x in xs:
+                    while x: if g(x): break 1 + x ;; x -= 1 ;;
+                    continue ;; ;;"#
+        ));
+    }
+
+    #[test]
+    fn codegen7() {
+        insta::assert_debug_snapshot!(compile(
+            r#"
+            func counter(step):
+                let n = 0 ;
+                func next(): n += step ;;
+                next ;;"#
+        ));
     }
 }
@@ -1,7 +1,55 @@
 extern crate eb_ast as ast;
+extern crate eb_diagnostics as diagnostics;
+extern crate eb_lexer as lexer;
 extern crate eb_parser as parser;
 extern crate eb_vm_ctx as vm_ctx;
 extern crate rustc_hash;
 
 pub mod expr;
 pub mod function;
+pub mod resolve;
+
+use diagnostics::Diagnostic;
+use lexer::location::Span;
+use std::{error::Error as StdErr, fmt};
+
+/// An error in a program that parsed, but cannot be compiled.
+#[derive(Debug)]
+pub enum Error {
+    BreakOutsideLoop(Span),
+    ContinueOutsideLoop(Span),
+    /// The parser recovered from a syntax error here.
+    SyntaxError(Span),
+}
+
+impl Error {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::BreakOutsideLoop(span)
+            | Self::ContinueOutsideLoop(span)
+            | Self::SyntaxError(span) => *span,
+        }
+    }
+
+    pub fn to_diagnostic(&self) -> Diagnostic {
+        Diagnostic::error(self.to_string()).with_span(self.span())
+    }
+}
+
+/// Converts an error returned by the compiler into a diagnostic. Returns `None` if `err` is not a
+/// compile error.
+pub fn diagnostic(err: &anyhow::Error) -> Option<Diagnostic> {
+    err.downcast_ref::<Error>().map(Error::to_diagnostic)
+}
+
+impl StdErr for Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BreakOutsideLoop(_) => write!(f, "`break` outside of a loop"),
+            Self::ContinueOutsideLoop(_) => write!(f, "`continue` outside of a loop"),
+            Self::SyntaxError(_) => write!(f, "cannot compile code with syntax errors"),
+        }
+    }
+}
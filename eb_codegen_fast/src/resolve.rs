@@ -0,0 +1,309 @@
+use anyhow::Result;
+use ast::{expr, function as func};
+use rustc_hash::FxHashMap;
+use std::convert::TryFrom;
+
+/// Where a variable lives.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Var {
+    /// A slot in the frame.
+    Local(u16),
+    /// A cell shared with closures.
+    Upvalue(u16),
+    Global,
+}
+
+/// The variables of a function. A name bound anywhere in the function refers to its local
+/// throughout the function. A local that nested functions capture lives in an upvalue instead of
+/// its slot. Names bound in no enclosing function refer to globals.
+#[derive(Debug, Default)]
+pub struct Scope {
+    slots: FxHashMap<String, u16>,
+    names: Vec<String>,
+    upvalues: FxHashMap<String, u16>,
+    /// Indices in the upvalues of the enclosing function of the upvalues captured from it. They
+    /// come first, before the upvalues for captured locals.
+    captures: Vec<u16>,
+    /// Name of the local a nested function refers to itself through.
+    itself: Option<String>,
+}
+
+impl Scope {
+    pub fn lookup(&self, name: &str) -> Var {
+        if let Some(idx) = self.upvalues.get(name) {
+            Var::Upvalue(*idx)
+        } else if let Some(slot) = self.slots.get(name) {
+            Var::Local(*slot)
+        } else {
+            Var::Global
+        }
+    }
+
+    pub fn slot(&self, name: &str) -> Option<u16> {
+        self.slots.get(name).copied()
+    }
+
+    /// Returns the names of the slots in order.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn captures(&self) -> &[u16] {
+        &self.captures
+    }
+
+    /// Returns the name of the local holding the function itself, which its prologue sets.
+    pub fn itself(&self) -> Option<&str> {
+        self.itself.as_deref()
+    }
+
+    /// Returns the number of upvalues for locals captured by nested functions.
+    pub fn captured_locals(&self) -> usize {
+        self.upvalues.len() - self.captures.len()
+    }
+
+    fn declare(&mut self, name: &str) -> Result<()> {
+        if self.slots.contains_key(name) {
+            return Ok(());
+        }
+        let slot = u16::try_from(self.names.len())
+            .map_err(|_| anyhow::anyhow!("too many local variables in a function"))?;
+        self.slots.insert(name.to_owned(), slot);
+        self.names.push(name.to_owned());
+        Ok(())
+    }
+
+    fn add_upvalue(&mut self, name: &str) -> Result<()> {
+        let idx = u16::try_from(self.upvalues.len())
+            .map_err(|_| anyhow::anyhow!("too many captured variables in a function"))?;
+        self.upvalues.insert(name.to_owned(), idx);
+        Ok(())
+    }
+}
+
+/// Resolves the variables of `func`, which is nested in the function whose scope is `parent`.
+/// Parameters take the first slots so that the arguments of a call are already in place.
+///
+/// A nested function that uses its own name refers to itself through a local of its own rather
+/// than through the variable of `parent`. Capturing that variable would make its closure hold
+/// itself, and never be freed.
+pub fn resolve(func: &func::Node, parent: &Scope) -> Result<Scope> {
+    let mut scope = Scope::default();
+    for param in func.params() {
+        scope.declare(param.name())?;
+    }
+    walk(func.body(), &mut |node| match node.kind() {
+        expr::Kind::Let(name, _) | expr::Kind::For(name, ..) => scope.declare(name),
+        expr::Kind::Function(func) => scope.declare(func.name()),
+        _ => Ok(()),
+    })?;
+    let name = func.name();
+    if parent.slot(name).is_some() && used_names(func).iter().any(|used| used == name) {
+        scope.declare(name)?;
+        scope.itself = Some(name.to_owned());
+    }
+
+    for name in free_names(func) {
+        if let Var::Upvalue(idx) = parent.lookup(&name) {
+            scope.add_upvalue(&name)?;
+            scope.captures.push(idx);
+        }
+    }
+    for nested in nested_functions(func.body()) {
+        for name in free_names(nested) {
+            if scope.slots.contains_key(&name) && !scope.upvalues.contains_key(&name) {
+                scope.add_upvalue(&name)?;
+            }
+        }
+    }
+    Ok(scope)
+}
+
+/// Returns the functions defined in `body`, not counting those nested in them, in order.
+pub fn nested_functions(body: &expr::Node) -> Vec<&func::Node> {
+    let mut funcs = vec![];
+    walk_nodes(body, &mut |node| {
+        if let expr::Kind::Function(func) = node.kind() {
+            funcs.push(&**func);
+        }
+    });
+    funcs
+}
+
+/// Returns the names `func` and the functions nested in it use without binding them, in order of
+/// appearance. The name of `func` itself is bound in it.
+fn free_names(func: &func::Node) -> Vec<String> {
+    let mut free = used_names(func);
+    free.retain(|name| name != func.name());
+    free
+}
+
+/// Returns the names `func` and the functions nested in it use without binding them, including
+/// the name of `func`, in order of appearance.
+fn used_names(func: &func::Node) -> Vec<String> {
+    let mut bound: Vec<&str> = func.params().iter().map(|p| p.name().as_str()).collect();
+    walk_nodes(func.body(), &mut |node| match node.kind() {
+        expr::Kind::Let(name, _) | expr::Kind::For(name, ..) => bound.push(name),
+        expr::Kind::Function(func) => bound.push(func.name()),
+        _ => {}
+    });
+
+    let mut free = vec![];
+    let mut add = |name: &str| {
+        if !bound.contains(&name) && !free.iter().any(|f| f == name) {
+            free.push(name.to_owned());
+        }
+    };
+    walk_nodes(func.body(), &mut |node| match node.kind() {
+        expr::Kind::Ident(name)
+        | expr::Kind::Assign(name, _)
+        | expr::Kind::CompoundAssign(_, name, _) => add(name),
+        expr::Kind::Function(func) => free_names(func).iter().for_each(|name| add(name)),
+        _ => {}
+    });
+    free
+}
+
+fn walk_nodes<'a>(node: &'a expr::Node, f: &mut impl FnMut(&'a expr::Node)) {
+    let _ = walk(node, &mut |node| {
+        f(node);
+        Ok(())
+    });
+}
+
+/// Calls `f` on `node` and the nodes in it, except those in the bodies of nested functions.
+fn walk<'a>(node: &'a expr::Node, f: &mut impl FnMut(&'a expr::Node) -> Result<()>) -> Result<()> {
+    f(node)?;
+    match node.kind() {
+        expr::Kind::Int(_)
+        | expr::Kind::Float(_)
+        | expr::Kind::Bool(_)
+        | expr::Kind::Str(_)
+        | expr::Kind::Ident(_)
+        | expr::Kind::Function(_)
+        | expr::Kind::Continue
+        | expr::Kind::Break(None)
+        | expr::Kind::Error => {}
+        expr::Kind::BinOp(_, lhs, rhs)
+        | expr::Kind::Index(lhs, rhs)
+        | expr::Kind::While(lhs, rhs)
+        | expr::Kind::For(_, lhs, rhs) => {
+            walk(lhs, f)?;
+            walk(rhs, f)?;
+        }
+        expr::Kind::UnaryOp(_, node)
+        | expr::Kind::Field(node, _)
+        | expr::Kind::Return(node)
+        | expr::Kind::Break(Some(node))
+        | expr::Kind::Let(_, node)
+        | expr::Kind::Assign(_, node)
+        | expr::Kind::CompoundAssign(_, _, node) => walk(node, f)?,
+        expr::Kind::Call(callee, args) => {
+            walk(callee, f)?;
+            for arg in args {
+                walk(arg, f)?;
+            }
+        }
+        expr::Kind::List(nodes) | expr::Kind::Exprs(nodes) => {
+            for node in nodes {
+                walk(node, f)?;
+            }
+        }
+        expr::Kind::If(cond, then_, else_) => {
+            walk(cond, f)?;
+            walk(then_, f)?;
+            if let Some(else_) = else_ {
+                walk(else_, f)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lexer::{source::Source, tokenize};
+    use parser::{function::parse, Context as ParserContext};
+
+    fn parse_str(s: &str) -> func::Node {
+        let source = Source::String(s.to_string());
+        let mut ctx = ParserContext::new(tokenize(&source));
+        parse(&mut ctx).expect("fail to parse")
+    }
+
+    #[test]
+    fn resolve1() {
+        let node = parse_str(
+            r#"
+            func f(a, b):
+                let c = a + b ;
+                for x in [c]: let c = x ;;
+                func g(): 1 ;;
+                d = g() ;;"#,
+        );
+        let scope = resolve(&node, &Scope::default()).unwrap();
+        assert_eq!(scope.names(), ["a", "b", "c", "x", "g"]);
+        assert_eq!(scope.slot("x"), Some(3));
+        assert_eq!(scope.lookup("x"), Var::Local(3));
+        assert_eq!(scope.lookup("d"), Var::Global);
+        assert_eq!(scope.captured_locals(), 0);
+    }
+
+    #[test]
+    fn resolve2() {
+        let node = parse_str(
+            r#"
+            func f(a, b):
+                let c = 1 ;
+                func g(x):
+                    func h(): a + x + c + print ;;
+                    h ;; ;;"#,
+        );
+        let f = resolve(&node, &Scope::default()).unwrap();
+        assert_eq!(f.lookup("a"), Var::Upvalue(0));
+        assert_eq!(f.lookup("b"), Var::Local(1));
+        assert_eq!(f.lookup("c"), Var::Upvalue(1));
+        assert_eq!(f.captured_locals(), 2);
+
+        let g = nested_functions(node.body())[0];
+        let g_scope = resolve(g, &f).unwrap();
+        assert_eq!(g_scope.captures(), [0, 1]);
+        assert_eq!(g_scope.lookup("a"), Var::Upvalue(0));
+        assert_eq!(g_scope.lookup("c"), Var::Upvalue(1));
+        assert_eq!(g_scope.lookup("x"), Var::Upvalue(2));
+        assert_eq!(g_scope.lookup("print"), Var::Global);
+
+        let h = nested_functions(g.body())[0];
+        let h_scope = resolve(h, &g_scope).unwrap();
+        assert_eq!(h_scope.captures(), [0, 2, 1]);
+        assert_eq!(h_scope.captured_locals(), 0);
+    }
+
+    #[test]
+    fn resolve3() {
+        let node = parse_str(
+            r#"
+            func f():
+                func g(n): g(n - 1) ;;
+                func h(n): func k(): h ;; n ;;
+                func f(): f ;; ;;"#,
+        );
+        let f = resolve(&node, &Scope::default()).unwrap();
+        assert_eq!(f.itself(), None);
+        assert_eq!(f.captured_locals(), 0);
+
+        // A nested function binds its own name if it or its nested functions use it.
+        let nested = nested_functions(node.body());
+        let g = resolve(nested[0], &f).unwrap();
+        assert_eq!(g.itself(), Some("g"));
+        assert_eq!(g.lookup("g"), Var::Local(1));
+        let h = resolve(nested[1], &f).unwrap();
+        assert_eq!(h.itself(), Some("h"));
+        assert_eq!(h.lookup("h"), Var::Upvalue(0));
+        assert!(h.captures().is_empty());
+        // `f` in the nested `f` is itself, not the enclosing one.
+        let inner = resolve(nested[2], &f).unwrap();
+        assert_eq!(inner.itself(), Some("f"));
+    }
+}
@@ -0,0 +1,110 @@
+extern crate eb_lexer as lexer;
+
+mod render;
+
+use lexer::{location::Span, source::SourceMap};
+use std::io::{self, IsTerminal, Write};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+/// A message about the source, rendered in the style of rustc:
+///
+/// ```text
+/// error: expected `:` after function parameters
+///  --> script.eb:1:10
+///   |
+/// 1 | func f(x) x;;
+///   |           ^
+/// ```
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    level: Level,
+    message: String,
+    span: Option<Span>,
+    label: Option<String>,
+    notes: Vec<String>,
+    helps: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(level: Level, message: impl Into<String>) -> Self {
+        Self {
+            level,
+            message: message.into(),
+            span: None,
+            label: None,
+            notes: vec![],
+            helps: vec![],
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self::new(Level::Error, message)
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self::new(Level::Warning, message)
+    }
+
+    /// Points the diagnostic at `span`, which is shown underlined.
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    /// Sets the text shown next to the underline.
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.helps.push(help.into());
+        self
+    }
+
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn notes(&self) -> &[String] {
+        &self.notes
+    }
+
+    pub fn helps(&self) -> &[String] {
+        &self.helps
+    }
+
+    /// Renders the diagnostic. `map` must contain the file `span` points into.
+    pub fn render(&self, map: &SourceMap, color: bool) -> String {
+        render::render(self, map, color)
+    }
+
+    /// Writes the diagnostic to stderr, colored if stderr is a terminal.
+    pub fn emit(&self, map: &SourceMap) {
+        let stderr = io::stderr();
+        let color = stderr.is_terminal();
+        let _ = stderr.lock().write_all(self.render(map, color).as_bytes());
+    }
+}
@@ -0,0 +1,173 @@
+use super::{Diagnostic, Level};
+use lexer::source::SourceMap;
+use std::fmt::Write;
+
+const TAB_WIDTH: usize = 4;
+
+struct Style {
+    color: bool,
+}
+
+impl Style {
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_owned()
+        }
+    }
+}
+
+pub fn render(diag: &Diagnostic, map: &SourceMap, color: bool) -> String {
+    let style = Style { color };
+    let (level, level_color) = match diag.level {
+        Level::Error => ("error", "1;31"),
+        Level::Warning => ("warning", "1;33"),
+    };
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{}{}",
+        style.paint(level_color, level),
+        style.paint("1", &format!(": {}", diag.message))
+    )
+    .unwrap();
+
+    let span = match diag.span {
+        Some(span) => span,
+        None => {
+            for (kind, text) in annotations(diag) {
+                writeln!(out, "  {} {}: {}", style.paint("1;34", "="), kind, text).unwrap();
+            }
+            return out;
+        }
+    };
+
+    let loc = map.location(span);
+    let gutter = " ".repeat(loc.line.to_string().len());
+    let bar = style.paint("1;34", "|");
+    writeln!(out, "{}{} {}", gutter, style.paint("1;34", "-->"), loc).unwrap();
+    writeln!(out, "{} {}", gutter, bar).unwrap();
+
+    // Only the first line of a multi-line span is shown.
+    let line = map.line(span.file, loc.line);
+    let col = loc.col as usize - 1;
+    let len = map.get(span.file).body()[span.start as usize..span.end as usize]
+        .chars()
+        .take_while(|&c| c != '\n')
+        .count();
+    let (line, col, len) = expand_tabs(line, col, len.max(1));
+    writeln!(
+        out,
+        "{} {} {}",
+        style.paint("1;34", &loc.line.to_string()),
+        bar,
+        line
+    )
+    .unwrap();
+    let underline = match &diag.label {
+        Some(label) => format!("{} {}", "^".repeat(len), label),
+        None => "^".repeat(len),
+    };
+    writeln!(
+        out,
+        "{} {} {}{}",
+        gutter,
+        bar,
+        " ".repeat(col),
+        style.paint(level_color, &underline)
+    )
+    .unwrap();
+
+    for (kind, text) in annotations(diag) {
+        writeln!(
+            out,
+            "{} {} {}: {}",
+            gutter,
+            style.paint("1;34", "="),
+            kind,
+            text
+        )
+        .unwrap();
+    }
+    out
+}
+
+fn annotations(diag: &Diagnostic) -> impl Iterator<Item = (&'static str, &String)> {
+    let notes = diag.notes.iter().map(|note| ("note", note));
+    let helps = diag.helps.iter().map(|help| ("help", help));
+    notes.chain(helps)
+}
+
+/// Replaces tabs in `line` with spaces, and adjusts the column and length of the underline so that
+/// it stays below the same characters.
+fn expand_tabs(line: &str, col: usize, len: usize) -> (String, usize, usize) {
+    let width = |c: char| if c == '\t' { TAB_WIDTH } else { 1 };
+    let mut chars = line.chars();
+    let col_ = chars.by_ref().take(col).map(width).sum();
+    let len_ = chars.take(len).map(width).sum::<usize>().max(1);
+    (line.replace('\t', &" ".repeat(TAB_WIDTH)), col_, len_)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lexer::{
+        location::Span,
+        source::{Source, SourceFile},
+    };
+
+    fn map(body: &str) -> SourceMap {
+        let mut map = SourceMap::default();
+        let file = SourceFile::from_string("test.eb".to_owned(), body.to_owned());
+        map.add(Source::File(file));
+        map
+    }
+
+    #[test]
+    fn render1() {
+        let map = map("func f(x)\n    x;;\n");
+        let id = lexer::location::FileId(0);
+        let diag = Diagnostic::error("expected `:` after function parameters")
+            .with_span(Span::new(id, 14, 15))
+            .with_label("expected `:` here")
+            .with_note("a function body starts with `:`")
+            .with_help("add `:` after `)`");
+        assert_eq!(
+            diag.render(&map, false),
+            "\
+error: expected `:` after function parameters
+ --> test.eb:2:5
+  |
+2 |     x;;
+  |     ^ expected `:` here
+  = note: a function body starts with `:`
+  = help: add `:` after `)`
+"
+        );
+    }
+
+    #[test]
+    fn render2() {
+        let map = map("x\nx\nx\nx\nx\nx\nx\nx\nx\n\tf(\"あい\" 1)\n");
+        let id = lexer::location::FileId(0);
+        let diag = Diagnostic::warning("unused value").with_span(Span::new(id, 21, 31));
+        assert_eq!(
+            diag.render(&map, false),
+            "\
+warning: unused value
+  --> test.eb:10:4
+   |
+10 |     f(\"あい\" 1)
+   |       ^^^^^^
+"
+        );
+        assert_eq!(
+            Diagnostic::error("oops")
+                .with_note("no span")
+                .render(&map, false),
+            "error: oops\n  = note: no span\n"
+        );
+        assert!(diag.render(&map, true).contains("\x1b[1;33mwarning\x1b[0m"));
+    }
+}
@@ -2,41 +2,85 @@ pub mod location;
 pub mod source;
 pub mod token;
 
-use source::Source;
+use location::{FileId, Span};
+use source::{Source, SourceMap};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    UnterminatedString(Span),
+    InvalidEscape(Span),
+    UnexpectedChar(Span, char),
+    /// A digit separator `_` ends a group of digits in a number.
+    TrailingSeparator(Span),
+}
+
+impl Error {
+    pub fn span(&self) -> Span {
+        match self {
+            Self::UnterminatedString(span)
+            | Self::InvalidEscape(span)
+            | Self::TrailingSeparator(span) => *span,
+            Self::UnexpectedChar(span, _) => *span,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnterminatedString(_) => write!(f, "unterminated string literal"),
+            Self::InvalidEscape(_) => write!(f, "invalid escape sequence"),
+            Self::UnexpectedChar(_, c) => write!(f, "unexpected character `{}`", c),
+            Self::TrailingSeparator(_) => {
+                write!(f, "digit separator `_` must be followed by a digit")
+            }
+        }
+    }
+}
 
 pub fn tokenize<'a>(source: &'a Source) -> token::TokenStream<'a> {
-    token::TokenStream::new(source)
+    token::TokenStream::new(source, FileId::default())
+}
+
+/// Tokenizes the file `id` registered in `map`, so that spans refer to it.
+pub fn tokenize_file(map: &SourceMap, id: FileId) -> token::TokenStream<'_> {
+    token::TokenStream::new(map.get(id), id)
+}
+
+#[cfg(test)]
+fn span(start: u32, end: u32) -> Span {
+    Span::new(FileId::default(), start, end)
 }
 
 #[test]
 fn tokenize1() {
-    use location::Location;
     use token::Token;
 
     let source = Source::String(r#"func f(x i32) i32: x;;"#.to_string());
-    let tokenize: Vec<Token> = tokenize(&source).into_iter().collect();
+    let tokenize: Vec<Token> = tokenize(&source).collect();
     let correct = vec![
-        Token::new(token::TokenKind::Ident("func"), Location(0)),
-        Token::new(token::TokenKind::Ident("f"), Location(5)),
+        Token::new(token::TokenKind::Ident("func"), span(0, 4)),
+        Token::new(token::TokenKind::Ident("f"), span(5, 6)),
         Token::new(
             token::TokenKind::OpenDelim(token::DelimKind::Paren),
-            Location(6),
+            span(6, 7),
         ),
-        Token::new(token::TokenKind::Ident("x"), Location(7)),
-        Token::new(token::TokenKind::Ident("i32"), Location(9)),
+        Token::new(token::TokenKind::Ident("x"), span(7, 8)),
+        Token::new(token::TokenKind::Ident("i32"), span(9, 12)),
         Token::new(
             token::TokenKind::CloseDelim(token::DelimKind::Paren),
-            Location(12),
+            span(12, 13),
         ),
-        Token::new(token::TokenKind::Ident("i32"), Location(14)),
+        Token::new(token::TokenKind::Ident("i32"), span(14, 17)),
         Token::new(
             token::TokenKind::Punct(token::PunctKind::Colon),
-            Location(17),
+            span(17, 18),
         ),
-        Token::new(token::TokenKind::Ident("x"), Location(19)),
+        Token::new(token::TokenKind::Ident("x"), span(19, 20)),
         Token::new(
             token::TokenKind::Punct(token::PunctKind::DoubleSemicolon),
-            Location(20),
+            span(20, 22),
         ),
     ];
     assert_eq!(tokenize.len(), correct.len());
@@ -45,7 +89,6 @@ fn tokenize1() {
 
 #[test]
 fn tokenize2() {
-    use location::Location;
     use token::{DelimKind, PunctKind, Token, TokenKind};
 
     let source = Source::String(
@@ -55,29 +98,226 @@ fn tokenize2() {
         "#
         .to_string(),
     );
-    let tokenize: Vec<Token> = tokenize(&source).into_iter().collect();
+    let tokenize: Vec<Token> = tokenize(&source).collect();
     let correct = vec![
-        Token::new(TokenKind::Ident("func"), Location(9)),
-        Token::new(TokenKind::Ident("f"), Location(14)),
-        Token::new(TokenKind::OpenDelim(DelimKind::Paren), Location(15)),
-        Token::new(TokenKind::Ident("x"), Location(16)),
-        Token::new(TokenKind::Ident("y"), Location(18)),
-        Token::new(TokenKind::Ident("i32"), Location(20)),
-        Token::new(TokenKind::CloseDelim(DelimKind::Paren), Location(23)),
-        Token::new(TokenKind::Ident("i32"), Location(25)),
-        Token::new(TokenKind::Punct(token::PunctKind::Colon), Location(28)),
-        Token::new(TokenKind::Ident("x"), Location(43)),
-        Token::new(TokenKind::Punct(PunctKind::Plus), Location(45)),
-        Token::new(TokenKind::Int("1"), Location(47)),
-        Token::new(TokenKind::Punct(PunctKind::Eq), Location(49)),
-        Token::new(TokenKind::Ident("y"), Location(52)),
-        Token::new(TokenKind::Punct(PunctKind::Minus), Location(54)),
-        Token::new(TokenKind::Int("1"), Location(56)),
+        Token::new(TokenKind::Ident("func"), span(9, 13)),
+        Token::new(TokenKind::Ident("f"), span(14, 15)),
+        Token::new(TokenKind::OpenDelim(DelimKind::Paren), span(15, 16)),
+        Token::new(TokenKind::Ident("x"), span(16, 17)),
+        Token::new(TokenKind::Ident("y"), span(18, 19)),
+        Token::new(TokenKind::Ident("i32"), span(20, 23)),
+        Token::new(TokenKind::CloseDelim(DelimKind::Paren), span(23, 24)),
+        Token::new(TokenKind::Ident("i32"), span(25, 28)),
+        Token::new(TokenKind::Punct(token::PunctKind::Colon), span(28, 29)),
+        Token::new(TokenKind::Ident("x"), span(43, 44)),
+        Token::new(TokenKind::Punct(PunctKind::Plus), span(45, 46)),
+        Token::new(TokenKind::Int("1"), span(47, 48)),
+        Token::new(TokenKind::Punct(PunctKind::Eq), span(49, 51)),
+        Token::new(TokenKind::Ident("y"), span(52, 53)),
+        Token::new(TokenKind::Punct(PunctKind::Minus), span(54, 55)),
+        Token::new(TokenKind::Int("1"), span(56, 57)),
         Token::new(
             TokenKind::Punct(token::PunctKind::DoubleSemicolon),
-            Location(57),
+            span(57, 59),
         ),
     ];
     assert_eq!(tokenize.len(), correct.len());
     assert!(tokenize.iter().zip(correct.iter()).all(|(a, b)| a == b))
 }
+
+#[test]
+fn tokenize3() {
+    use token::{DelimKind, Token, TokenKind};
+
+    let source = Source::String(r#"f("a\tb\n", "\"\\", "\u{3042}\u{1F600}", "")"#.to_string());
+    let tokenize: Vec<Token> = tokenize(&source).collect();
+    let correct = vec![
+        Token::new(TokenKind::Ident("f"), span(0, 1)),
+        Token::new(TokenKind::OpenDelim(DelimKind::Paren), span(1, 2)),
+        Token::new(TokenKind::Str("a\tb\n".to_string()), span(2, 10)),
+        Token::new(TokenKind::Punct(token::PunctKind::Comma), span(10, 11)),
+        Token::new(TokenKind::Str("\"\\".to_string()), span(12, 18)),
+        Token::new(TokenKind::Punct(token::PunctKind::Comma), span(18, 19)),
+        Token::new(
+            TokenKind::Str("\u{3042}\u{1F600}".to_string()),
+            span(20, 39),
+        ),
+        Token::new(TokenKind::Punct(token::PunctKind::Comma), span(39, 40)),
+        Token::new(TokenKind::Str("".to_string()), span(41, 43)),
+        Token::new(TokenKind::CloseDelim(DelimKind::Paren), span(43, 44)),
+    ];
+    assert_eq!(tokenize, correct);
+}
+
+#[test]
+fn tokenize4() {
+    use token::{Token, TokenKind};
+
+    let source = Source::String(r#"x "a\qb" @ "abc"#.to_string());
+    let tokens: Vec<Token> = tokenize(&source).collect();
+    let correct = vec![
+        Token::new(TokenKind::Ident("x"), span(0, 1)),
+        Token::new(
+            TokenKind::Error(Error::InvalidEscape(span(4, 6))),
+            span(2, 8),
+        ),
+        Token::new(
+            TokenKind::Error(Error::UnexpectedChar(span(9, 10), '@')),
+            span(9, 10),
+        ),
+        Token::new(
+            TokenKind::Error(Error::UnterminatedString(span(11, 15))),
+            span(11, 15),
+        ),
+    ];
+    assert_eq!(tokens, correct);
+
+    for bad in &[r#""\u{}""#, r#""\u{110000}""#, r#""\u{+41}""#, r#""\u{41""#] {
+        let source = Source::String(bad.to_string());
+        let tokens: Vec<Token> = tokenize(&source).collect();
+        assert!(matches!(
+            tokens[0].kind(),
+            TokenKind::Error(Error::InvalidEscape(_))
+        ));
+    }
+}
+
+#[test]
+fn tokenize5() {
+    use token::{PunctKind, Token, TokenKind};
+
+    let source = Source::String(r#"1.5 2e10 .5 1.5E-3 3 1e x"#.to_string());
+    let tokens: Vec<Token> = tokenize(&source).collect();
+    let correct = vec![
+        Token::new(TokenKind::Float("1.5"), span(0, 3)),
+        Token::new(TokenKind::Float("2e10"), span(4, 8)),
+        Token::new(TokenKind::Float(".5"), span(9, 11)),
+        Token::new(TokenKind::Float("1.5E-3"), span(12, 18)),
+        Token::new(TokenKind::Int("3"), span(19, 20)),
+        Token::new(TokenKind::Int("1"), span(21, 22)),
+        Token::new(TokenKind::Ident("e"), span(22, 23)),
+        Token::new(TokenKind::Ident("x"), span(24, 25)),
+    ];
+    assert_eq!(tokens, correct);
+
+    let source = Source::String(r#"1.-2"#.to_string());
+    let tokens: Vec<Token> = tokenize(&source).collect();
+    assert_eq!(tokens[0], Token::new(TokenKind::Int("1"), span(0, 1)));
+    assert_eq!(
+        tokens[1],
+        Token::new(TokenKind::Punct(PunctKind::Dot), span(1, 2))
+    );
+    assert_eq!(
+        tokens[2],
+        Token::new(TokenKind::Punct(PunctKind::Minus), span(2, 3))
+    );
+}
+
+#[test]
+fn tokenize6() {
+    use token::{Token, TokenKind};
+
+    let source = Source::String(r#"0x1F 0o17 0b1010_1010 1_000_000 0xZZ 1_0.2_5"#.to_string());
+    let tokens: Vec<Token> = tokenize(&source).collect();
+    let correct = vec![
+        Token::new(TokenKind::Int("0x1F"), span(0, 4)),
+        Token::new(TokenKind::Int("0o17"), span(5, 9)),
+        Token::new(TokenKind::Int("0b1010_1010"), span(10, 21)),
+        Token::new(TokenKind::Int("1_000_000"), span(22, 31)),
+        Token::new(TokenKind::Int("0xZZ"), span(32, 36)),
+        Token::new(TokenKind::Float("1_0.2_5"), span(37, 44)),
+    ];
+    assert_eq!(tokens, correct);
+}
+
+#[test]
+fn tokenize7() {
+    use token::{PunctKind, Token, TokenKind};
+
+    let source = Source::String(r#"a<=b<c>=d>e&&!f||g!=h**i*j"#.to_string());
+    let tokens: Vec<Token> = tokenize(&source).collect();
+    let puncts: Vec<PunctKind> = tokens
+        .iter()
+        .filter_map(|tok| match tok.kind() {
+            TokenKind::Punct(p) => Some(*p),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        puncts,
+        [
+            PunctKind::Le,
+            PunctKind::Lt,
+            PunctKind::Ge,
+            PunctKind::Gt,
+            PunctKind::And,
+            PunctKind::Not,
+            PunctKind::Or,
+            PunctKind::Neq,
+            PunctKind::StarStar,
+            PunctKind::Star,
+        ]
+    );
+    assert_eq!(tokens.len(), 20);
+    assert_eq!(
+        tokens[10],
+        Token::new(TokenKind::Punct(PunctKind::Not), span(13, 14))
+    );
+}
+
+#[test]
+fn tokenize8() {
+    use token::{PunctKind, Token, TokenKind};
+
+    let source = Source::String(r#"x=y==z+=1-=2*=3/=4"#.to_string());
+    let puncts: Vec<PunctKind> = tokenize(&source)
+        .filter_map(|tok: Token| match tok.kind() {
+            TokenKind::Punct(p) => Some(*p),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        puncts,
+        [
+            PunctKind::Assign,
+            PunctKind::Eq,
+            PunctKind::PlusEq,
+            PunctKind::MinusEq,
+            PunctKind::StarEq,
+            PunctKind::SlashEq,
+        ]
+    );
+}
+
+#[test]
+fn tokenize9() {
+    use token::{PunctKind, Token, TokenKind};
+
+    let source = Source::String(r#"1_ 0x1_ 1_.5 1.5_ 1_e3 1e3_ 1__0"#.to_string());
+    let tokens: Vec<Token> = tokenize(&source).collect();
+    let error = |start, end| {
+        Token::new(
+            TokenKind::Error(Error::TrailingSeparator(span(start, end))),
+            span(start, end),
+        )
+    };
+    let correct = vec![
+        error(0, 2),
+        error(3, 7),
+        error(8, 12),
+        error(13, 17),
+        error(18, 22),
+        error(23, 27),
+        Token::new(TokenKind::Int("1__0"), span(28, 32)),
+    ];
+    assert_eq!(tokens, correct);
+
+    // The lexer leaves negation to the parser, even for `i64::MIN`.
+    let source = Source::String(r#"-9223372036854775808"#.to_string());
+    let tokens: Vec<Token> = tokenize(&source).collect();
+    let correct = vec![
+        Token::new(TokenKind::Punct(PunctKind::Minus), span(0, 1)),
+        Token::new(TokenKind::Int("9223372036854775808"), span(1, 20)),
+    ];
+    assert_eq!(tokens, correct);
+}
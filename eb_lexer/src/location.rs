@@ -1,8 +1,51 @@
+use std::fmt;
+
+/// Identifies the source file a `Span` belongs to.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct FileId(pub u32);
+
+/// A byte range `start..end` in a source file.
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
-pub struct Location(pub u32);
+pub struct Span {
+    pub file: FileId,
+    pub start: u32,
+    pub end: u32,
+}
+
+impl Span {
+    pub fn new(file: FileId, start: u32, end: u32) -> Self {
+        Self { file, start, end }
+    }
+
+    /// Returns the smallest span that covers both `self` and `other`.
+    pub fn merge(self, other: Self) -> Self {
+        debug_assert_eq!(self.file, other.file);
+        Self {
+            file: self.file,
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+
+    pub fn len(self) -> u32 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.start == self.end
+    }
+}
+
+/// A position resolved by `SourceMap::location`. Lines and columns start at 1.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Location<'a> {
+    pub file: &'a str,
+    pub line: u32,
+    pub col: u32,
+}
 
-impl Location {
-    pub fn loc(self) -> u32 {
-        self.0
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
     }
 }
@@ -1,3 +1,4 @@
+use super::location::{FileId, Location, Span};
 use std::{fs::read_to_string, io};
 
 pub enum Source {
@@ -10,6 +11,30 @@ pub struct SourceFile {
     body: String,
 }
 
+/// Owns the sources of a run, assigns each a `FileId` and resolves spans to lines and columns.
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<Entry>,
+}
+
+struct Entry {
+    source: Source,
+    /// Byte offsets at which each line starts.
+    line_starts: Vec<u32>,
+}
+
+impl Entry {
+    fn new(source: Source) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.body().match_indices('\n').map(|(i, _)| i as u32 + 1))
+            .collect();
+        Self {
+            source,
+            line_starts,
+        }
+    }
+}
+
 impl Source {
     pub fn as_file(&self) -> Option<&SourceFile> {
         match self {
@@ -25,10 +50,18 @@ impl Source {
         }
     }
 
+    /// Returns the file name, or `<string>` for a source that is not a file.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::File(file) => file.name(),
+            Self::String(_) => "<string>",
+        }
+    }
+
     pub fn body(&self) -> &String {
         match self {
             Self::File(file) => file.body(),
-            Self::String(s) => &s,
+            Self::String(s) => s,
         }
     }
 }
@@ -41,6 +74,11 @@ impl SourceFile {
         })
     }
 
+    /// Creates a file that is not backed by the file system, such as a REPL input.
+    pub fn from_string(name: String, body: String) -> Self {
+        Self { name, body }
+    }
+
     pub fn name(&self) -> &str {
         self.name.as_str()
     }
@@ -49,3 +87,96 @@ impl SourceFile {
         &self.body
     }
 }
+
+impl SourceMap {
+    pub fn add(&mut self, source: Source) -> FileId {
+        self.files.push(Entry::new(source));
+        FileId(self.files.len() as u32 - 1)
+    }
+
+    /// Replaces the source of the file `id`, so that its id can be reused without the map
+    /// growing. Spans into the old source must no longer be resolved.
+    pub fn replace(&mut self, id: FileId, source: Source) {
+        self.files[id.0 as usize] = Entry::new(source);
+    }
+
+    pub fn get(&self, id: FileId) -> &Source {
+        &self.files[id.0 as usize].source
+    }
+
+    /// Returns the 1-based line and column of the byte `offset` in the file `id`. Columns count
+    /// characters, not bytes.
+    pub fn line_col(&self, id: FileId, offset: u32) -> (u32, u32) {
+        let entry = &self.files[id.0 as usize];
+        let line = entry.line_starts.partition_point(|&start| start <= offset) - 1;
+        let start = entry.line_starts[line] as usize;
+        let col = entry.source.body()[start..offset as usize].chars().count();
+        (line as u32 + 1, col as u32 + 1)
+    }
+
+    /// Returns the text of the 1-based `line` in the file `id`, without the line break.
+    pub fn line(&self, id: FileId, line: u32) -> &str {
+        let entry = &self.files[id.0 as usize];
+        let body = entry.source.body();
+        let start = entry.line_starts[line as usize - 1] as usize;
+        let end = entry
+            .line_starts
+            .get(line as usize)
+            .map_or(body.len(), |&end| end as usize - 1);
+        body[start..end].trim_end_matches('\r')
+    }
+
+    /// Resolves the start of `span` to a `file:line:col` location.
+    pub fn location(&self, span: Span) -> Location<'_> {
+        let (line, col) = self.line_col(span.file, span.start);
+        Location {
+            file: self.get(span.file).name(),
+            line,
+            col,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn source_map1() {
+        let mut map = SourceMap::default();
+        let a = map.add(Source::String("x\nfoo bar\n\nbaz".to_string()));
+        let b = map.add(Source::File(SourceFile::from_string(
+            "b.eb".to_string(),
+            "\"あい\" + x\r\ny".to_string(),
+        )));
+        assert_eq!((a, b), (FileId(0), FileId(1)));
+
+        assert_eq!(map.line_col(a, 0), (1, 1));
+        assert_eq!(map.line_col(a, 1), (1, 2));
+        assert_eq!(map.line_col(a, 2), (2, 1));
+        assert_eq!(map.line_col(a, 6), (2, 5));
+        assert_eq!(map.line_col(a, 10), (3, 1));
+        assert_eq!(map.line_col(a, 11), (4, 1));
+        assert_eq!(map.line(a, 2), "foo bar");
+        assert_eq!(map.line(a, 3), "");
+        assert_eq!(map.line(a, 4), "baz");
+
+        // `あ` and `い` take three bytes each but one column.
+        assert_eq!(map.line_col(b, 11), (1, 8));
+        assert_eq!(map.line(b, 1), "\"あい\" + x");
+        assert_eq!(map.line_col(b, 14), (2, 1));
+        assert_eq!(map.location(Span::new(b, 11, 12)).to_string(), "b.eb:1:8");
+        assert_eq!(map.location(Span::new(a, 6, 9)).to_string(), "<string>:2:5");
+    }
+
+    #[test]
+    fn source_map2() {
+        let mut map = SourceMap::default();
+        let a = map.add(Source::String("foo\nbar".to_string()));
+        map.replace(a, Source::String("x\n\ny\nz".to_string()));
+        assert_eq!(map.get(a).body(), "x\n\ny\nz");
+        assert_eq!(map.line_col(a, 5), (4, 1));
+        assert_eq!(map.line(a, 3), "y");
+        assert_eq!(map.add(Source::String("".to_string())), FileId(1));
+    }
+}
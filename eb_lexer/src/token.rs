@@ -1,28 +1,35 @@
-use super::{location::Location, source::Source};
+use super::{
+    location::{FileId, Span},
+    source::Source,
+    Error,
+};
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until, take_while1},
-    character::complete::{char, digit1, multispace0},
-    combinator::map,
+    bytes::complete::{tag, take_until, take_while, take_while1},
+    character::complete::{char, digit1, multispace0, one_of},
+    combinator::{map, opt, recognize},
     error::VerboseError,
     multi::many1,
-    sequence::{preceded, terminated, tuple},
+    sequence::{pair, preceded, terminated, tuple},
     IResult,
 };
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Token<'a> {
     kind: TokenKind<'a>,
-    loc: Location,
+    span: Span,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenKind<'a> {
     Int(&'a str),
+    Float(&'a str),
+    Str(String),
     Ident(&'a str),
     OpenDelim(DelimKind),
     CloseDelim(DelimKind),
     Punct(PunctKind),
+    Error(Error),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -37,33 +44,48 @@ pub enum PunctKind {
     Plus,
     Minus,
     Star,
+    StarStar,
     Slash,
     Eq,
     Neq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    Not,
     Colon,
     Semicolon,
     DoubleSemicolon,
     Comma,
+    Dot,
+    Assign,
+    PlusEq,
+    MinusEq,
+    StarEq,
+    SlashEq,
 }
 
 pub struct TokenStream<'a> {
     source: &'a Source,
+    file: FileId,
     tokens: Vec<Token<'a>>,
     body: &'a String,
     cur: &'a str,
 }
 
 impl<'a> Token<'a> {
-    pub fn new(kind: TokenKind<'a>, loc: Location) -> Self {
-        Self { kind, loc }
+    pub fn new(kind: TokenKind<'a>, span: Span) -> Self {
+        Self { kind, span }
     }
 
-    pub fn kind(&self) -> &TokenKind {
+    pub fn kind(&self) -> &TokenKind<'a> {
         &self.kind
     }
 
-    pub fn loc(&self) -> &Location {
-        &self.loc
+    pub fn span(&self) -> Span {
+        self.span
     }
 }
 
@@ -75,12 +97,19 @@ impl<'a> TokenKind<'a> {
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(s: &str) -> Option<Self> {
         match s {
             ":" => Some(Self::Punct(PunctKind::Colon)),
             ";" => Some(Self::Punct(PunctKind::Semicolon)),
             ";;" => Some(Self::Punct(PunctKind::DoubleSemicolon)),
             "," => Some(Self::Punct(PunctKind::Comma)),
+            "." => Some(Self::Punct(PunctKind::Dot)),
+            "=" => Some(Self::Punct(PunctKind::Assign)),
+            "+=" => Some(Self::Punct(PunctKind::PlusEq)),
+            "-=" => Some(Self::Punct(PunctKind::MinusEq)),
+            "*=" => Some(Self::Punct(PunctKind::StarEq)),
+            "/=" => Some(Self::Punct(PunctKind::SlashEq)),
             "(" => Some(Self::OpenDelim(DelimKind::Paren)),
             ")" => Some(Self::CloseDelim(DelimKind::Paren)),
             "{" => Some(Self::OpenDelim(DelimKind::Brace)),
@@ -90,19 +119,77 @@ impl<'a> TokenKind<'a> {
             "+" => Some(Self::Punct(PunctKind::Plus)),
             "-" => Some(Self::Punct(PunctKind::Minus)),
             "*" => Some(Self::Punct(PunctKind::Star)),
+            "**" => Some(Self::Punct(PunctKind::StarStar)),
             "/" => Some(Self::Punct(PunctKind::Slash)),
             "==" => Some(Self::Punct(PunctKind::Eq)),
             "!=" => Some(Self::Punct(PunctKind::Neq)),
+            "<" => Some(Self::Punct(PunctKind::Lt)),
+            "<=" => Some(Self::Punct(PunctKind::Le)),
+            ">" => Some(Self::Punct(PunctKind::Gt)),
+            ">=" => Some(Self::Punct(PunctKind::Ge)),
+            "&&" => Some(Self::Punct(PunctKind::And)),
+            "||" => Some(Self::Punct(PunctKind::Or)),
+            "!" => Some(Self::Punct(PunctKind::Not)),
             _ => None,
         }
     }
 }
 
+impl DelimKind {
+    pub fn open_str(self) -> &'static str {
+        match self {
+            Self::Paren => "(",
+            Self::Bracket => "[",
+            Self::Brace => "{",
+        }
+    }
+
+    pub fn close_str(self) -> &'static str {
+        match self {
+            Self::Paren => ")",
+            Self::Bracket => "]",
+            Self::Brace => "}",
+        }
+    }
+}
+
+impl PunctKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Plus => "+",
+            Self::Minus => "-",
+            Self::Star => "*",
+            Self::StarStar => "**",
+            Self::Slash => "/",
+            Self::Eq => "==",
+            Self::Neq => "!=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+            Self::And => "&&",
+            Self::Or => "||",
+            Self::Not => "!",
+            Self::Colon => ":",
+            Self::Semicolon => ";",
+            Self::DoubleSemicolon => ";;",
+            Self::Comma => ",",
+            Self::Dot => ".",
+            Self::Assign => "=",
+            Self::PlusEq => "+=",
+            Self::MinusEq => "-=",
+            Self::StarEq => "*=",
+            Self::SlashEq => "/=",
+        }
+    }
+}
+
 impl<'a> TokenStream<'a> {
-    pub fn new(source: &'a Source) -> Self {
+    pub fn new(source: &'a Source, file: FileId) -> Self {
         let body = source.body();
         Self {
             source,
+            file,
             tokens: vec![],
             body,
             cur: body.as_str(),
@@ -112,51 +199,179 @@ impl<'a> TokenStream<'a> {
     pub fn source(&self) -> &Source {
         self.source
     }
+
+    pub fn file(&self) -> FileId {
+        self.file
+    }
+
+    /// Returns the span of `s`, which must be a slice of the source body.
+    fn span(&self, s: &str) -> Span {
+        let start = (s.as_ptr() as usize - self.body.as_ptr() as usize) as u32;
+        Span::new(self.file, start, start + s.len() as u32)
+    }
+
+    /// Lexes the string literal at the head of `source`, which starts with `"`.
+    fn string(&self, source: &'a str) -> (&'a str, TokenKind<'a>, Span) {
+        let mut lit = String::new();
+        let mut error = None;
+        let mut chars = source.char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    let kind = match error {
+                        Some(err) => TokenKind::Error(err),
+                        None => TokenKind::Str(lit),
+                    };
+                    return (&source[i + 1..], kind, self.span(&source[..i + 1]));
+                }
+                '\\' => match escape(&source[i + 1..]) {
+                    Some((c, len)) => {
+                        lit.push(c);
+                        for _ in 0..len {
+                            chars.next();
+                        }
+                    }
+                    None => {
+                        let len = source[i + 1..].chars().next().map_or(0, char::len_utf8);
+                        let span = self.span(&source[i..i + 1 + len]);
+                        error.get_or_insert(Error::InvalidEscape(span));
+                    }
+                },
+                c => lit.push(c),
+            }
+        }
+        let span = self.span(source);
+        (
+            &source[source.len()..],
+            TokenKind::Error(Error::UnterminatedString(span)),
+            span,
+        )
+    }
+
+    fn token(&self, cur: &'a str) -> Option<(&'a str, Token<'a>)> {
+        let span = |source: &str| -> Span { self.span(source) };
+        if cur.starts_with('"') {
+            let (source, kind, span) = self.string(cur);
+            return Some((source, Token::new(kind, span)));
+        }
+        if let Ok(ok) = alt((
+            map(float, |f: &str| number(TokenKind::Float(f), f, span(f))),
+            map(int, |i: &str| number(TokenKind::Int(i), i, span(i))),
+            map(delimiter, |s: &str| {
+                Token::new(TokenKind::from_str(s).unwrap(), span(s))
+            }),
+            map(symbol, |s: &str| {
+                Token::new(TokenKind::from_str(s).unwrap(), span(s))
+            }),
+            map(identifier, |i: &str| {
+                Token::new(TokenKind::Ident(i), span(i))
+            }),
+        ))(cur)
+        {
+            return Some(ok);
+        }
+        let c = cur.chars().next()?;
+        let span = span(&cur[..c.len_utf8()]);
+        let err = Error::UnexpectedChar(span, c);
+        Some((
+            &cur[c.len_utf8()..],
+            Token::new(TokenKind::Error(err), span),
+        ))
+    }
 }
 
 impl<'a> Iterator for TokenStream<'a> {
     type Item = Token<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let bgn = self.body.as_str().as_ptr() as usize;
-        let loc = |source: &str| -> Location { Location((source.as_ptr() as usize - bgn) as u32) };
-        if let Some((source, token)) = preceded(
-            spaces,
-            alt((
-                map(digit1, |i: &str| Token::new(TokenKind::Int(i), loc(i))),
-                map(delimiter, |s: &str| {
-                    Token::new(TokenKind::from_str(s).unwrap(), loc(s))
-                }),
-                map(symbol, |s: &str| {
-                    Token::new(TokenKind::from_str(s).unwrap(), loc(s))
-                }),
-                map(identifier, |i: &str| {
-                    Token::new(TokenKind::Ident(i), loc(i))
-                }),
-            )),
-        )(self.cur)
-        .ok()
-        {
-            self.cur = source;
-            self.tokens.push(token.clone());
-            return Some(token);
+        let (cur, _) = spaces(self.cur).ok()?;
+        let (source, token) = self.token(cur)?;
+        self.cur = source;
+        self.tokens.push(token.clone());
+        Some(token)
+    }
+}
+
+/// Returns a token of `kind` for the number `lit`, or an error if a `_` in it is not followed by
+/// a digit.
+fn number<'a>(kind: TokenKind<'a>, lit: &str, span: Span) -> Token<'a> {
+    let trailing = match kind {
+        TokenKind::Float(_) => ["_.", "_e", "_E"].iter().any(|s| lit.contains(s)),
+        _ => false,
+    };
+    if trailing || lit.ends_with('_') {
+        return Token::new(TokenKind::Error(Error::TrailingSeparator(span)), span);
+    }
+    Token::new(kind, span)
+}
+
+/// Decodes the escape sequence following a backslash. Returns the character and the number of
+/// characters the sequence spans.
+fn escape(source: &str) -> Option<(char, usize)> {
+    match source.chars().next()? {
+        'n' => Some(('\n', 1)),
+        't' => Some(('\t', 1)),
+        '"' => Some(('"', 1)),
+        '\\' => Some(('\\', 1)),
+        'u' => {
+            let digits = source.strip_prefix("u{")?;
+            let end = digits.find('}')?;
+            if end == 0 || end > 6 || !digits[..end].chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            let c = char::from_u32(u32::from_str_radix(&digits[..end], 16).ok()?)?;
+            Some((c, end + 3))
         }
-        None
+        _ => None,
     }
 }
 
 pub fn symbol(source: &str) -> IResult<&str, &str, VerboseError<&str>> {
     alt((
-        tag(":"),
-        tag(";;"),
-        tag(";"),
-        tag(","),
-        tag("+"),
-        tag("-"),
-        tag("*"),
-        tag("/"),
-        tag("=="),
-        tag("!="),
+        alt((tag(":"), tag(";;"), tag(";"), tag(","), tag("."))),
+        alt((tag("+="), tag("-="), tag("*="), tag("/="))),
+        alt((tag("+"), tag("-"), tag("**"), tag("*"), tag("/"))),
+        alt((
+            tag("=="),
+            tag("!="),
+            tag("<="),
+            tag(">="),
+            tag("<"),
+            tag(">"),
+        )),
+        alt((tag("&&"), tag("||"), tag("!"), tag("="))),
+    ))(source)
+}
+
+/// Recognizes a decimal integer or one prefixed with `0x`, `0o` or `0b`. Digits may be separated
+/// by `_`. A `_` that ends the digits is reported by `number`, and digits of a prefixed integer
+/// are checked by the parser.
+pub fn int(source: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    alt((
+        recognize(pair(
+            alt((tag("0x"), tag("0o"), tag("0b"))),
+            take_while(|c: char| c.is_alphanumeric() || c == '_'),
+        )),
+        digits,
+    ))(source)
+}
+
+pub fn float(source: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    alt((
+        recognize(tuple((digits, char('.'), digits, opt(exponent)))),
+        recognize(tuple((char('.'), digits, opt(exponent)))),
+        recognize(tuple((digits, exponent))),
+    ))(source)
+}
+
+fn exponent(source: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    recognize(tuple((one_of("eE"), opt(one_of("+-")), digits)))(source)
+}
+
+fn digits(source: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    recognize(pair(
+        digit1,
+        take_while(|c: char| c.is_ascii_digit() || c == '_'),
     ))(source)
 }
 
@@ -1,125 +1,211 @@
-use super::{function, Context, Error};
+use super::{function, unexpected, Context, Error};
 use crate::{
     ast::expr,
-    lexer::token::{DelimKind, PunctKind, TokenKind},
+    lexer::{
+        location::Span,
+        token::{DelimKind, PunctKind, TokenKind},
+    },
 };
-use anyhow::Result;
+use anyhow::{Context as _, Result};
+use std::num::IntErrorKind;
 
-pub fn parse(ctx: &mut Context) -> Result<expr::Node> {
-    parse_binop_eq_ne(ctx)
+#[derive(Clone, Copy)]
+enum Assoc {
+    Left,
+    Right,
 }
 
-fn parse_binop_eq_ne(ctx: &mut Context) -> Result<expr::Node> {
-    let mut lhs = parse_binop_add_sub(ctx)?;
-    loop {
-        let loc = ctx.cur_loc();
+/// Binary operators with their precedence and associativity. Higher precedence binds tighter.
+const INFIX_OPS: &[(PunctKind, expr::BinOpKind, u8, Assoc)] = &[
+    (PunctKind::Or, expr::BinOpKind::Or, 1, Assoc::Left),
+    (PunctKind::And, expr::BinOpKind::And, 2, Assoc::Left),
+    (PunctKind::Eq, expr::BinOpKind::Eq, 3, Assoc::Left),
+    (PunctKind::Neq, expr::BinOpKind::Neq, 3, Assoc::Left),
+    (PunctKind::Lt, expr::BinOpKind::Lt, 4, Assoc::Left),
+    (PunctKind::Le, expr::BinOpKind::Le, 4, Assoc::Left),
+    (PunctKind::Gt, expr::BinOpKind::Gt, 4, Assoc::Left),
+    (PunctKind::Ge, expr::BinOpKind::Ge, 4, Assoc::Left),
+    (PunctKind::Plus, expr::BinOpKind::Add, 5, Assoc::Left),
+    (PunctKind::Minus, expr::BinOpKind::Sub, 5, Assoc::Left),
+    (PunctKind::Star, expr::BinOpKind::Mul, 6, Assoc::Left),
+    (PunctKind::Slash, expr::BinOpKind::Div, 6, Assoc::Left),
+    (PunctKind::StarStar, expr::BinOpKind::Pow, 8, Assoc::Right),
+];
+
+/// Prefix operators. They bind looser than `**`, so `-2 ** 2` is `-(2 ** 2)`.
+const PREFIX_OPS: &[(PunctKind, expr::UnaryOpKind)] = &[
+    (PunctKind::Not, expr::UnaryOpKind::Not),
+    (PunctKind::Minus, expr::UnaryOpKind::Neg),
+];
+const PREFIX_PREC: u8 = 7;
+
+/// Postfix operators, such as calls, bind tighter than anything else.
+const POSTFIX_PREC: u8 = 9;
+
+/// Assignment operators. `None` is plain assignment.
+const ASSIGN_OPS: &[(PunctKind, Option<expr::BinOpKind>)] = &[
+    (PunctKind::Assign, None),
+    (PunctKind::PlusEq, Some(expr::BinOpKind::Add)),
+    (PunctKind::MinusEq, Some(expr::BinOpKind::Sub)),
+    (PunctKind::StarEq, Some(expr::BinOpKind::Mul)),
+    (PunctKind::SlashEq, Some(expr::BinOpKind::Div)),
+];
 
-        let eq = ctx.skip_punct(PunctKind::Eq);
-        let neq = ctx.skip_punct(PunctKind::Neq);
+pub fn parse(ctx: &mut Context) -> Result<expr::Node> {
+    let lhs = parse_expr(ctx, 0)?;
+    parse_assign(ctx, lhs)
+}
 
-        if !eq && !neq {
-            break;
-        }
+/// Parses an assignment to `lhs` if one follows. Assignment binds loosest and is
+/// right-associative, so `x = y = 1` assigns `1` to both.
+fn parse_assign(ctx: &mut Context, lhs: expr::Node) -> Result<expr::Node> {
+    let op = match ctx.peek().map(|tok| tok.kind()) {
+        Some(TokenKind::Punct(punct)) => ASSIGN_OPS.iter().find(|(p, _)| p == punct),
+        _ => None,
+    };
+    let op = match op {
+        Some(&(_, op)) if !ends_with_block(&lhs) => op,
+        _ => return Ok(lhs),
+    };
+    ctx.next();
+    let name = match lhs.kind() {
+        expr::Kind::Ident(name) => name.to_owned(),
+        _ => return Err(Error::InvalidAssignTarget(lhs.span()).into()),
+    };
+    let rhs = parse(ctx)?;
+    let span = lhs.span().merge(rhs.span());
+    let kind = match op {
+        Some(op) => expr::Kind::CompoundAssign(op, name, Box::new(rhs)),
+        None => expr::Kind::Assign(name, Box::new(rhs)),
+    };
+    Ok(expr::Node::new(kind, span))
+}
 
-        let loc = loc?;
-        let rhs = parse_binop_add_sub(ctx)?;
-
-        lhs = expr::Node::new(
-            expr::Kind::BinOp(
-                if eq {
-                    expr::BinOpKind::Eq
-                } else {
-                    expr::BinOpKind::Neq
-                },
-                Box::new(lhs.clone()),
-                Box::new(rhs),
-            ),
-            loc,
-        );
+/// Parses an expression made of operators whose precedence is at least `min_prec`.
+fn parse_expr(ctx: &mut Context, min_prec: u8) -> Result<expr::Node> {
+    let mut lhs = parse_prefix(ctx)?;
+    // A block closed by `;;` ends the expression; whatever follows starts a new one.
+    while !ends_with_block(&lhs) {
+        let kind = match ctx.peek() {
+            Some(tok) => tok.kind(),
+            None => break,
+        };
+        if is_postfix(kind) {
+            if POSTFIX_PREC < min_prec {
+                break;
+            }
+            lhs = parse_postfix(ctx, lhs)?;
+            continue;
+        }
+        let (op, prec, assoc) = match INFIX_OPS
+            .iter()
+            .find(|(punct, ..)| kind == &TokenKind::Punct(*punct))
+        {
+            Some(&(_, op, prec, assoc)) if prec >= min_prec => (op, prec, assoc),
+            _ => break,
+        };
+        ctx.next();
+
+        let rhs = parse_expr(
+            ctx,
+            match assoc {
+                Assoc::Left => prec + 1,
+                Assoc::Right => prec,
+            },
+        )?;
+        let span = lhs.span().merge(rhs.span());
+
+        lhs = expr::Node::new(expr::Kind::BinOp(op, Box::new(lhs), Box::new(rhs)), span);
     }
     Ok(lhs)
 }
 
-fn parse_binop_add_sub(ctx: &mut Context) -> Result<expr::Node> {
-    let mut lhs = parse_binop_mul_div(ctx)?;
-    loop {
-        let loc = ctx.cur_loc();
+fn parse_prefix(ctx: &mut Context) -> Result<expr::Node> {
+    let op = match ctx.peek().map(|tok| tok.kind()) {
+        Some(TokenKind::Punct(punct)) => PREFIX_OPS.iter().find(|(p, _)| p == punct),
+        _ => None,
+    };
+    let op = match op {
+        Some((_, op)) => *op,
+        None => return parse_primary(ctx),
+    };
+    let span = ctx.next().unwrap().span();
+    if let (expr::UnaryOpKind::Neg, Some(node)) = (op, parse_min_int(ctx, span)) {
+        return Ok(node);
+    }
+    let operand = parse_expr(ctx, PREFIX_PREC)?;
+    let span = span.merge(operand.span());
+    Ok(expr::Node::new(
+        expr::Kind::UnaryOp(op, Box::new(operand)),
+        span,
+    ))
+}
 
-        let plus = ctx.skip_punct(PunctKind::Plus);
-        let minus = ctx.skip_punct(PunctKind::Minus);
+fn is_postfix(kind: &TokenKind) -> bool {
+    matches!(
+        kind,
+        TokenKind::OpenDelim(DelimKind::Paren | DelimKind::Bracket)
+            | TokenKind::Punct(PunctKind::Dot)
+    )
+}
 
-        if !plus && !minus {
-            break;
+fn parse_postfix(ctx: &mut Context, base: expr::Node) -> Result<expr::Node> {
+    match ctx.next().unwrap().kind() {
+        // Call
+        TokenKind::OpenDelim(DelimKind::Paren) => {
+            let args = parse_call_args(ctx)?;
+            let span = ctx.span_from(base.span());
+            Ok(expr::Node::new(
+                expr::Kind::Call(Box::new(base), args),
+                span,
+            ))
         }
-
-        let loc = loc?;
-        let rhs = parse_binop_mul_div(ctx)?;
-
-        lhs = expr::Node::new(
-            expr::Kind::BinOp(
-                if plus {
-                    expr::BinOpKind::Add
-                } else {
-                    expr::BinOpKind::Sub
-                },
-                Box::new(lhs.clone()),
-                Box::new(rhs),
-            ),
-            loc,
-        );
+        // Index
+        TokenKind::OpenDelim(DelimKind::Bracket) => {
+            let index = parse(ctx)?;
+            ctx.expect_close_delim(DelimKind::Bracket)
+                .context("after index")?;
+            let span = ctx.span_from(base.span());
+            Ok(expr::Node::new(
+                expr::Kind::Index(Box::new(base), Box::new(index)),
+                span,
+            ))
+        }
+        // Field access
+        TokenKind::Punct(PunctKind::Dot) => {
+            let field = ctx.expect_any_ident().context("after `.`")?;
+            let field = field.kind().as_ident().unwrap().to_string();
+            let span = ctx.span_from(base.span());
+            Ok(expr::Node::new(
+                expr::Kind::Field(Box::new(base), field),
+                span,
+            ))
+        }
+        _ => unreachable!(),
     }
-    Ok(lhs)
 }
 
-fn parse_binop_mul_div(ctx: &mut Context) -> Result<expr::Node> {
-    let mut lhs = parse_postfix(ctx)?;
-    loop {
-        let loc = ctx.cur_loc();
+fn parse_list_elems(ctx: &mut Context) -> Result<Vec<expr::Node>> {
+    if ctx.skip_close_delim(DelimKind::Bracket) {
+        return Ok(vec![]);
+    }
 
-        let star = ctx.skip_punct(PunctKind::Star);
-        let slash = ctx.skip_punct(PunctKind::Slash);
+    let mut elems = vec![];
+
+    loop {
+        elems.push(parse(ctx)?);
 
-        if !star && !slash {
-            break;
+        if ctx.skip_punct(PunctKind::Comma) {
+            continue;
         }
 
-        let loc = loc?;
-        let rhs = parse_postfix(ctx)?;
-
-        lhs = expr::Node::new(
-            expr::Kind::BinOp(
-                if star {
-                    expr::BinOpKind::Mul
-                } else {
-                    expr::BinOpKind::Div
-                },
-                Box::new(lhs.clone()),
-                Box::new(rhs),
-            ),
-            loc,
-        );
-    }
-    Ok(lhs)
-}
+        ctx.expect_close_delim(DelimKind::Bracket)
+            .context("after list element")?;
 
-fn parse_postfix(ctx: &mut Context) -> Result<expr::Node> {
-    let base = parse_primary(ctx)?;
-    let peek = match ctx.peek() {
-        Some(peek) => peek,
-        None => return Ok(base),
-    };
-    let loc = *peek.loc();
-    match peek.kind() {
-        // Call
-        TokenKind::OpenDelim(DelimKind::Paren) => {
-            assert!(ctx.next().is_some());
-            Ok(expr::Node::new(
-                expr::Kind::Call(Box::new(base), parse_call_args(ctx)?),
-                loc,
-            ))
-        }
-        _ => Ok(base),
+        break;
     }
+
+    Ok(elems)
 }
 
 fn parse_call_args(ctx: &mut Context) -> Result<Vec<expr::Node>> {
@@ -137,7 +223,8 @@ fn parse_call_args(ctx: &mut Context) -> Result<Vec<expr::Node>> {
             continue;
         }
 
-        ctx.expect_close_delim(DelimKind::Paren)?;
+        ctx.expect_close_delim(DelimKind::Paren)
+            .context("after argument")?;
 
         break;
     }
@@ -147,42 +234,145 @@ fn parse_call_args(ctx: &mut Context) -> Result<Vec<expr::Node>> {
 
 fn parse_primary(ctx: &mut Context) -> Result<expr::Node> {
     let peek = ctx.peek().ok_or(Error::EOF)?;
-    let loc = *peek.loc();
+    let span = peek.span();
     match peek.kind() {
         TokenKind::Int(int) => {
-            let int = int.parse().unwrap();
+            let int = parse_int(int, span)?;
             ctx.next().unwrap();
-            Ok(expr::Node::new(expr::Kind::Int(int), loc))
+            Ok(expr::Node::new(expr::Kind::Int(int), span))
+        }
+        TokenKind::Float(float) => {
+            let float = float.replace('_', "").parse().unwrap();
+            ctx.next().unwrap();
+            Ok(expr::Node::new(expr::Kind::Float(float), span))
+        }
+        TokenKind::Str(s) => {
+            let s = s.to_owned();
+            ctx.next().unwrap();
+            Ok(expr::Node::new(expr::Kind::Str(s), span))
+        }
+        TokenKind::OpenDelim(DelimKind::Paren) => {
+            ctx.next().unwrap();
+            let mut inner = parse(ctx)?;
+            ctx.expect_close_delim(DelimKind::Paren)
+                .context("to close `(`")?;
+            inner.set_span(ctx.span_from(span));
+            Ok(inner)
+        }
+        TokenKind::OpenDelim(DelimKind::Bracket) => {
+            ctx.next().unwrap();
+            let elems = parse_list_elems(ctx)?;
+            Ok(expr::Node::new(
+                expr::Kind::List(elems),
+                ctx.span_from(span),
+            ))
+        }
+        TokenKind::Ident(ident @ ("true" | "false")) => {
+            let b = *ident == "true";
+            ctx.next().unwrap();
+            Ok(expr::Node::new(expr::Kind::Bool(b), span))
+        }
+        TokenKind::Ident(ident) if ident == &"func" => {
+            let func = function::parse(ctx)?;
+            let span = func.span();
+            Ok(expr::Node::new(expr::Kind::Function(Box::new(func)), span))
+        }
+        TokenKind::Ident(ident) if ident == &"if" => {
+            let kind = parse_if(ctx)?;
+            Ok(expr::Node::new(kind, ctx.span_from(span)))
+        }
+        TokenKind::Ident(ident) if ident == &"let" => {
+            let kind = parse_let(ctx)?;
+            Ok(expr::Node::new(kind, ctx.span_from(span)))
+        }
+        TokenKind::Ident(ident) if ident == &"while" => {
+            let kind = parse_while(ctx)?;
+            Ok(expr::Node::new(kind, ctx.span_from(span)))
+        }
+        TokenKind::Ident(ident) if ident == &"for" => {
+            let kind = parse_for(ctx)?;
+            Ok(expr::Node::new(kind, ctx.span_from(span)))
+        }
+        TokenKind::Ident(ident) if ident == &"break" => {
+            let kind = parse_break(ctx)?;
+            Ok(expr::Node::new(kind, ctx.span_from(span)))
+        }
+        TokenKind::Ident(ident) if ident == &"continue" => {
+            ctx.next().unwrap();
+            Ok(expr::Node::new(expr::Kind::Continue, span))
         }
-        TokenKind::Ident(ident) if ident == &"func" => Ok(expr::Node::new(
-            expr::Kind::Function(Box::new(function::parse(ctx)?)),
-            loc,
-        )),
-        TokenKind::Ident(ident) if ident == &"if" => Ok(expr::Node::new(parse_if(ctx)?, loc)),
         TokenKind::Ident(ident) if ident == &"return" => {
-            Ok(expr::Node::new(parse_return(ctx)?, loc))
+            let kind = parse_return(ctx)?;
+            Ok(expr::Node::new(kind, ctx.span_from(span)))
         }
         TokenKind::Ident(ident) => {
             let ident = ident.to_string();
             ctx.next().unwrap();
-            Ok(expr::Node::new(expr::Kind::Ident(ident), loc))
+            Ok(expr::Node::new(expr::Kind::Ident(ident), span))
         }
-        _ => Err(Error::ExpectedAny(loc, "integer value or identifier").into()),
+        _ => Err(unexpected(
+            peek,
+            Error::ExpectedAny(span, "number, string or identifier"),
+        )),
+    }
+}
+
+/// Parses the integer literal after `-`, whose span is `minus`, if its magnitude is that of
+/// `i64::MIN`. That magnitude is out of range on its own, so the literal cannot be negated.
+fn parse_min_int(ctx: &mut Context, minus: Span) -> Option<expr::Node> {
+    let (digits, radix) = match ctx.peek()?.kind() {
+        TokenKind::Int(int) => int_digits(int),
+        _ => return None,
+    };
+    if u64::from_str_radix(&digits, radix).ok()? != i64::MIN.unsigned_abs() {
+        return None;
     }
+    ctx.next().unwrap();
+    Some(expr::Node::new(
+        expr::Kind::Int(i64::MIN),
+        ctx.span_from(minus),
+    ))
+}
+
+/// Returns the digits of an integer literal without its prefix and separators, and their radix.
+fn int_digits(int: &str) -> (String, u32) {
+    let (digits, radix) = match int.get(..2) {
+        Some("0x") => (&int[2..], 16),
+        Some("0o") => (&int[2..], 8),
+        Some("0b") => (&int[2..], 2),
+        _ => (int, 10),
+    };
+    (digits.replace('_', ""), radix)
+}
+
+fn parse_int(int: &str, span: Span) -> Result<i64> {
+    let (digits, radix) = int_digits(int);
+    i64::from_str_radix(&digits, radix).map_err(|e| match e.kind() {
+        IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => Error::IntOutOfRange(span).into(),
+        _ => Error::InvalidInt(span).into(),
+    })
 }
 
 fn parse_if(ctx: &mut Context) -> Result<expr::Kind> {
     ctx.expect_keyword("if")?;
     let cond = parse(ctx)?;
-    ctx.expect_punct(PunctKind::Colon)?;
+    ctx.expect_punct(PunctKind::Colon)
+        .context("after `if` condition")?;
     let then_expr = parse_body(ctx)?;
-    let else_expr;
-    if ctx.skip_keyword("else") {
-        ctx.expect_punct(PunctKind::Colon)?;
-        else_expr = Some(Box::new(parse_body(ctx)?));
+    let else_expr = if !ctx.skip_keyword("else") {
+        None
+    } else if matches!(
+        ctx.peek().map(|tok| tok.kind()),
+        Some(TokenKind::Ident("if"))
+    ) {
+        // `else if` chains the next `if` as the else branch.
+        let span = ctx.cur_span()?;
+        let kind = parse_if(ctx)?;
+        Some(Box::new(expr::Node::new(kind, ctx.span_from(span))))
     } else {
-        else_expr = None;
-    }
+        ctx.expect_punct(PunctKind::Colon).context("after `else`")?;
+        Some(Box::new(parse_body(ctx)?))
+    };
     Ok(expr::Kind::If(
         Box::new(cond),
         Box::new(then_expr),
@@ -190,31 +380,166 @@ fn parse_if(ctx: &mut Context) -> Result<expr::Kind> {
     ))
 }
 
+fn parse_let(ctx: &mut Context) -> Result<expr::Kind> {
+    ctx.expect_keyword("let")?;
+    let name = ctx.expect_any_ident().context("after `let`")?;
+    let name = name.kind().as_ident().unwrap().to_string();
+    ctx.expect_punct(PunctKind::Assign)
+        .context("after variable name")?;
+    Ok(expr::Kind::Let(name, Box::new(parse(ctx)?)))
+}
+
+fn parse_while(ctx: &mut Context) -> Result<expr::Kind> {
+    ctx.expect_keyword("while")?;
+    let cond = parse(ctx)?;
+    ctx.expect_punct(PunctKind::Colon)
+        .context("after `while` condition")?;
+    Ok(expr::Kind::While(
+        Box::new(cond),
+        Box::new(parse_body(ctx)?),
+    ))
+}
+
+fn parse_for(ctx: &mut Context) -> Result<expr::Kind> {
+    ctx.expect_keyword("for")?;
+    let var = ctx.expect_any_ident().context("after `for`")?;
+    let var = var.kind().as_ident().unwrap().to_string();
+    ctx.expect_keyword("in").context("after loop variable")?;
+    let iterable = parse(ctx)?;
+    ctx.expect_punct(PunctKind::Colon)
+        .context("after loop iterable")?;
+    Ok(expr::Kind::For(
+        var,
+        Box::new(iterable),
+        Box::new(parse_body(ctx)?),
+    ))
+}
+
+/// Parses `break`, followed by a value unless the expression ends right after it.
+fn parse_break(ctx: &mut Context) -> Result<expr::Kind> {
+    ctx.expect_keyword("break")?;
+    let has_value = !matches!(
+        ctx.peek().map(|tok| tok.kind()),
+        None | Some(
+            TokenKind::Punct(PunctKind::Semicolon | PunctKind::DoubleSemicolon | PunctKind::Comma)
+                | TokenKind::CloseDelim(_)
+        )
+    );
+    let val = if has_value {
+        Some(Box::new(parse(ctx)?))
+    } else {
+        None
+    };
+    Ok(expr::Kind::Break(val))
+}
+
 fn parse_return(ctx: &mut Context) -> Result<expr::Kind> {
     ctx.expect_keyword("return")?;
     Ok(expr::Kind::Return(Box::new(parse(ctx)?)))
 }
 
+/// Parses a block: expressions separated by `;` and closed by `;;`.
 pub fn parse_body(ctx: &mut Context) -> Result<expr::Node> {
-    let loc = ctx.cur_loc();
+    let span = ctx.cur_span().context("in block, expected `;;`")?;
 
     if ctx.skip_punct(PunctKind::DoubleSemicolon) {
-        return Ok(expr::Node::new(expr::Kind::Exprs(vec![]), loc?));
+        return Ok(expr::Node::new(expr::Kind::Exprs(vec![]), span));
     }
 
     let mut body = vec![];
 
     loop {
-        body.push(parse(ctx)?);
+        if ctx.peek().is_none() {
+            return Err(Error::EOF).context("in block, expected `;;`");
+        }
 
-        if ctx.skip_punct(PunctKind::Semicolon) {
-            continue;
+        if parse_stmt(ctx, &mut body)? == Some(PunctKind::DoubleSemicolon) {
+            let span = ctx.span_from(span);
+            return Ok(expr::Node::new(expr::Kind::Exprs(body), span));
+        }
+    }
+}
+
+/// Parses a whole file. Top-level expressions are separated by `;` or `;;`, and the last one needs
+/// no terminator.
+pub fn parse_program(ctx: &mut Context) -> Result<expr::Node> {
+    let span = Span::new(ctx.file(), 0, 0);
+    let mut body = vec![];
+    while ctx.peek().is_some() {
+        parse_stmt(ctx, &mut body)?;
+    }
+    let span = ctx.span_from(span);
+    Ok(expr::Node::new(expr::Kind::Exprs(body), span))
+}
+
+/// Parses an expression into `body` and consumes the `;` or `;;` after it, which is returned.
+/// Expressions ending with a block are already terminated by its `;;`, so they need neither.
+fn parse_stmt(ctx: &mut Context, body: &mut Vec<expr::Node>) -> Result<Option<PunctKind>> {
+    if let Some(tok) = ctx.peek() {
+        if let TokenKind::Punct(p @ (PunctKind::Semicolon | PunctKind::DoubleSemicolon)) =
+            tok.kind()
+        {
+            let (p, span) = (*p, tok.span());
+            let err = Error::ExpectedAny(span, "expression");
+            ctx.report(anyhow::Error::new(err).context(format!("before `{}`", p.as_str())));
+            ctx.next();
+            return Ok(Some(p));
         }
+    }
+
+    let node = parse_or_recover(ctx)?;
+    let terminated = ends_with_block(&node) || matches!(node.kind(), expr::Kind::Error);
+    body.push(node);
 
-        if ctx.skip_punct(PunctKind::DoubleSemicolon) {
-            return Ok(expr::Node::new(expr::Kind::Exprs(body), loc?));
+    for p in [PunctKind::Semicolon, PunctKind::DoubleSemicolon] {
+        if ctx.skip_punct(p) {
+            return Ok(Some(p));
+        }
+    }
+    if let Some(tok) = ctx.peek() {
+        if !terminated {
+            let err = Error::ExpectedAny(tok.span(), "`;` or `;;`");
+            ctx.report(anyhow::Error::new(err).context("after expression"));
         }
     }
+    Ok(None)
+}
+
+/// Returns whether `node` ends with a block closed by `;;`.
+fn ends_with_block(node: &expr::Node) -> bool {
+    match node.kind() {
+        expr::Kind::Function(_)
+        | expr::Kind::If(..)
+        | expr::Kind::While(..)
+        | expr::Kind::For(..) => true,
+        expr::Kind::Return(val)
+        | expr::Kind::UnaryOp(_, val)
+        | expr::Kind::BinOp(_, _, val)
+        | expr::Kind::Let(_, val)
+        | expr::Kind::Assign(_, val)
+        | expr::Kind::CompoundAssign(_, _, val) => ends_with_block(val),
+        expr::Kind::Break(Some(val)) => ends_with_block(val),
+        _ => false,
+    }
+}
+
+/// Parses an expression. A syntax error is reported to `ctx` and the tokens up to the next
+/// synchronization point are replaced with an `Error` node. Running out of input is not recovered
+/// from.
+pub fn parse_or_recover(ctx: &mut Context) -> Result<expr::Node> {
+    let start = ctx.cur_span()?;
+    match parse(ctx) {
+        Err(e) if !matches!(e.downcast_ref(), Some(Error::EOF)) => {
+            ctx.report(e);
+            ctx.synchronize();
+            let span = match ctx.prev_span() {
+                Some(prev) if prev.end > start.start => start.merge(prev),
+                _ => start,
+            };
+            Ok(expr::Node::new(expr::Kind::Error, span))
+        }
+        result => result,
+    }
 }
 
 #[cfg(test)]
@@ -292,4 +617,328 @@ mod test {
     fn parse11() {
         insta::assert_debug_snapshot!(parse_str(r#"return 123"#));
     }
+
+    #[test]
+    fn parse12() {
+        insta::assert_debug_snapshot!(parse_str(r#"f("hello\n", "\u{3042}")"#));
+    }
+
+    #[test]
+    fn parse13() {
+        let source = Source::String(r#"f("abc)"#.to_string());
+        let mut ctx = Context::new(tokenize(&source));
+        let err = parse(&mut ctx).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(Error::Lexer(lexer::Error::UnterminatedString(_)))
+        ));
+    }
+
+    #[test]
+    fn parse14() {
+        insta::assert_debug_snapshot!(parse_str(r#"1.5 * .5 - 2e3"#));
+    }
+
+    #[test]
+    fn parse15() {
+        let int = |s: &str| match parse_str(s).kind() {
+            expr::Kind::Int(i) => *i,
+            _ => panic!(),
+        };
+        assert_eq!(int("0x1F"), 31);
+        assert_eq!(int("0o17"), 15);
+        assert_eq!(int("0b1010_1010"), 170);
+        assert_eq!(int("1_000_000"), 1_000_000);
+        assert_eq!(int("0x7fff_ffff_ffff_ffff"), i64::MAX);
+        assert_eq!(int("-9223372036854775808"), i64::MIN);
+        assert_eq!(int("-0x8000_0000_0000_0000"), i64::MIN);
+        let node = parse_str("-9223372036854775808");
+        assert_eq!((node.span().start, node.span().end), (0, 20));
+    }
+
+    #[test]
+    fn parse16() {
+        let err = |s: &str| {
+            let source = Source::String(s.to_string());
+            let mut ctx = Context::new(tokenize(&source));
+            parse(&mut ctx).unwrap_err().downcast::<Error>().unwrap()
+        };
+        assert!(matches!(
+            err("x + 9223372036854775808"),
+            Error::IntOutOfRange(Span {
+                start: 4,
+                end: 23,
+                ..
+            })
+        ));
+        assert!(matches!(
+            err("-9223372036854775809"),
+            Error::IntOutOfRange(Span {
+                start: 1,
+                end: 20,
+                ..
+            })
+        ));
+        assert!(matches!(
+            err("0x8000000000000000"),
+            Error::IntOutOfRange(Span {
+                start: 0,
+                end: 18,
+                ..
+            })
+        ));
+        assert!(matches!(
+            err("0b102"),
+            Error::InvalidInt(Span {
+                start: 0,
+                end: 5,
+                ..
+            })
+        ));
+        assert!(matches!(
+            err("0x"),
+            Error::InvalidInt(Span {
+                start: 0,
+                end: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn parse17() {
+        let node = parse_str(r#"f(1) * x + "a""#);
+        assert_eq!((node.span().start, node.span().end), (0, 14));
+        match node.kind() {
+            expr::Kind::BinOp(_, lhs, rhs) => {
+                assert_eq!((lhs.span().start, lhs.span().end), (0, 8));
+                assert_eq!((rhs.span().start, rhs.span().end), (11, 14));
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn parse18() {
+        let source = Source::String(r#"f(1 2)"#.to_string());
+        let mut ctx = Context::new(tokenize(&source));
+        let err = parse(&mut ctx).unwrap_err();
+        let diag = crate::diagnostic(&err).unwrap();
+        assert_eq!(diag.message(), "expected `)` after argument");
+
+        let source = Source::String(r#"if x "a\qb": 1 ;;"#.to_string());
+        let mut ctx = Context::new(tokenize(&source));
+        let err = parse(&mut ctx).unwrap_err();
+        let diag = crate::diagnostic(&err).unwrap();
+        assert_eq!(diag.message(), "invalid escape sequence");
+    }
+
+    #[test]
+    fn parse19() {
+        let source = Source::String(r#"f(1 2); x + ; g(x) ) if x: 1 ;; 3 ;;"#.to_string());
+        let mut ctx = Context::new(tokenize(&source));
+        let node = parse_body(&mut ctx).expect("fail to recover");
+        let messages: Vec<_> = ctx
+            .errors()
+            .iter()
+            .map(|e| crate::diagnostic(e).unwrap().message().to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "expected `)` after argument",
+                "expected number, string or identifier",
+                "expected `;` or `;;` after expression",
+                "expected number, string or identifier",
+            ]
+        );
+        insta::assert_debug_snapshot!(node);
+        assert!(ctx.finish().is_err());
+        assert!(ctx.errors().is_empty());
+    }
+
+    #[test]
+    fn parse20() {
+        let messages = |s: &str, program: bool| {
+            let source = Source::String(s.to_string());
+            let mut ctx = Context::new(tokenize(&source));
+            let result = if program {
+                parse_program(&mut ctx)
+            } else {
+                parse_body(&mut ctx)
+            };
+            let mut messages = vec![];
+            if let Err(e) = result {
+                messages.push(crate::diagnostic(&e).unwrap().message().to_string());
+            }
+            for e in ctx.errors() {
+                messages.push(crate::diagnostic(e).unwrap().message().to_string());
+            }
+            messages
+        };
+
+        assert!(messages("f(1); x ;;", false).is_empty());
+        assert!(messages("if x: 1 ;; y ;;", false).is_empty());
+        assert_eq!(
+            messages("f(1) x ;;", false),
+            ["expected `;` or `;;` after expression"]
+        );
+        assert_eq!(
+            messages("x ; ;;", false),
+            ["expected expression before `;;`"]
+        );
+        assert_eq!(
+            messages("x ; y", false),
+            ["unexpected end of file in block, expected `;;`"]
+        );
+        assert_eq!(
+            messages("", false),
+            ["unexpected end of file in block, expected `;;`"]
+        );
+
+        assert!(messages("", true).is_empty());
+        assert!(messages("func f(x): x ;; f(1)", true).is_empty());
+        assert!(messages("1 ; 2 ;; 3 ;;", true).is_empty());
+        assert_eq!(
+            messages("1 2", true),
+            ["expected `;` or `;;` after expression"]
+        );
+        assert_eq!(messages("1 ;; ;", true), ["expected expression before `;`"]);
+        assert_eq!(
+            messages("func f(x): x", true),
+            ["unexpected end of file in block, expected `;;`"]
+        );
+    }
+
+    #[test]
+    fn parse21() {
+        let source = Source::String("func f(x): x ;;\nf(1) ; f(2)".to_string());
+        let mut ctx = Context::new(tokenize(&source));
+        insta::assert_debug_snapshot!(parse_program(&mut ctx).expect("fail to parse"));
+        assert!(ctx.errors().is_empty());
+    }
+
+    #[test]
+    fn parse22() {
+        insta::assert_debug_snapshot!(parse_str("a || !b && c <= -1 + 2"));
+        assert_eq!(parse_str("true").kind(), &expr::Kind::Bool(true));
+        assert_eq!(parse_str("false").kind(), &expr::Kind::Bool(false));
+    }
+
+    #[test]
+    fn parse23() {
+        insta::assert_debug_snapshot!(parse_str("-(a + b) * c ** d ** e"));
+
+        let source = Source::String(r#"(a + b"#.to_string());
+        let mut ctx = Context::new(tokenize(&source));
+        let err = parse(&mut ctx).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Error::EOF)));
+
+        let source = Source::String(r#"(a + b ;"#.to_string());
+        let mut ctx = Context::new(tokenize(&source));
+        let err = parse(&mut ctx).unwrap_err();
+        let diag = crate::diagnostic(&err).unwrap();
+        assert_eq!(diag.message(), "expected `)` to close `(`");
+    }
+
+    #[test]
+    fn parse24() {
+        insta::assert_debug_snapshot!(parse_str("f(1)(2)[x + 1].y(3)"));
+        assert!(matches!(parse_str("-a.b").kind(), expr::Kind::UnaryOp(..)));
+
+        let source = Source::String(r#"a.+b"#.to_string());
+        let mut ctx = Context::new(tokenize(&source));
+        let err = parse(&mut ctx).unwrap_err();
+        let diag = crate::diagnostic(&err).unwrap();
+        assert_eq!(diag.message(), "expected identifier after `.`");
+
+        let source = Source::String(r#"a[1 2]"#.to_string());
+        let mut ctx = Context::new(tokenize(&source));
+        let err = parse(&mut ctx).unwrap_err();
+        let diag = crate::diagnostic(&err).unwrap();
+        assert_eq!(diag.message(), "expected `]` after index");
+    }
+
+    #[test]
+    fn parse25() {
+        let source = Source::String(r#"if a: 1 ;; else if b: 2 ;; else: 3 ;;"#.to_string());
+        let mut ctx = Context::new(tokenize(&source));
+        insta::assert_debug_snapshot!(parse_program(&mut ctx).expect("fail to parse"));
+        assert!(ctx.errors().is_empty());
+    }
+
+    #[test]
+    fn parse26() {
+        let source = Source::String(r#"let x = 1 ; x += y = 2 * 3"#.to_string());
+        let mut ctx = Context::new(tokenize(&source));
+        insta::assert_debug_snapshot!(parse_program(&mut ctx).expect("fail to parse"));
+        assert!(ctx.errors().is_empty());
+
+        let messages = |s: &str| {
+            let source = Source::String(s.to_string());
+            let mut ctx = Context::new(tokenize(&source));
+            let err = parse(&mut ctx).unwrap_err();
+            crate::diagnostic(&err).unwrap().message().to_string()
+        };
+        assert_eq!(messages("f(x) = 1"), "invalid left-hand side of assignment");
+        assert_eq!(messages("let 1 = 1"), "expected identifier after `let`");
+        assert_eq!(messages("let x 1"), "expected `=` after variable name");
+    }
+
+    #[test]
+    fn parse27() {
+        let source = Source::String(
+            r#"
+            while i < 10:
+                if i == 5: break ;;
+                i += 1 ;;
+            for x in [1, "a", []]: continue ;;
+            let y = for c in s: break c ;;
+            y"#
+            .to_string(),
+        );
+        let mut ctx = Context::new(tokenize(&source));
+        insta::assert_debug_snapshot!(parse_program(&mut ctx).expect("fail to parse"));
+        assert!(ctx.errors().is_empty());
+    }
+
+    #[test]
+    fn parse28() {
+        let messages = |s: &str| {
+            let source = Source::String(s.to_string());
+            let mut ctx = Context::new(tokenize(&source));
+            let err = parse(&mut ctx).unwrap_err();
+            crate::diagnostic(&err).unwrap().message().to_string()
+        };
+        assert_eq!(
+            messages("while x 1 ;;"),
+            "expected `:` after `while` condition"
+        );
+        assert_eq!(
+            messages("for 1 in x: ;;"),
+            "expected identifier after `for`"
+        );
+        assert_eq!(
+            messages("for x of y: ;;"),
+            "expected `in` after loop variable"
+        );
+        assert_eq!(
+            messages("[1, 2"),
+            "unexpected end of file after list element"
+        );
+        assert_eq!(messages("[1 2]"), "expected `]` after list element");
+    }
+
+    #[test]
+    fn parse29() {
+        let source = Source::String(
+            r#"
+            let x = -if true: 1 ;; else: 2 ;;
+            (3)"#
+                .to_string(),
+        );
+        let mut ctx = Context::new(tokenize(&source));
+        insta::assert_debug_snapshot!(parse_program(&mut ctx).expect("fail to parse"));
+        assert!(ctx.errors().is_empty());
+    }
 }
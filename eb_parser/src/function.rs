@@ -1,23 +1,31 @@
-use super::{expr, Context};
+use super::{expr, Context, Error};
 use crate::{
     ast::function as ast_func,
     lexer::token::{DelimKind, PunctKind},
 };
-use anyhow::Result;
+use anyhow::{Context as _, Result};
 
 pub fn parse(ctx: &mut Context) -> Result<ast_func::Node> {
-    ctx.expect_keyword("func")?;
+    let span = ctx.expect_keyword("func")?.span();
     let ident = ctx
-        .expect_any_ident()?
+        .expect_any_ident()
+        .context("after `func`")?
         .kind()
         .as_ident()
         .unwrap()
         .to_string();
-    ctx.expect_open_delim(DelimKind::Paren)?;
+    ctx.expect_open_delim(DelimKind::Paren)
+        .context("after function name")?;
     let params = parse_parameters(ctx)?;
-    ctx.expect_punct(PunctKind::Colon)?;
+    ctx.expect_punct(PunctKind::Colon)
+        .context("after function parameters")?;
     let body = expr::parse_body(ctx)?;
-    Ok(ast_func::Node::new(ident, params, body))
+    Ok(ast_func::Node::new(
+        ident,
+        params,
+        body,
+        ctx.span_from(span),
+    ))
 }
 
 fn parse_parameters(ctx: &mut Context) -> Result<Vec<ast_func::Param>> {
@@ -27,19 +35,19 @@ fn parse_parameters(ctx: &mut Context) -> Result<Vec<ast_func::Param>> {
     let mut params = vec![];
 
     loop {
-        let param = ctx
-            .expect_any_ident()?
-            .kind()
-            .as_ident()
-            .unwrap()
-            .to_string();
-        params.push(ast_func::Param::new(param));
+        let param = ctx.expect_any_ident()?;
+        let name = param.kind().as_ident().unwrap().to_string();
+        if params.iter().any(|p: &ast_func::Param| *p.name() == name) {
+            return Err(Error::DuplicateParam(param.span(), name).into());
+        }
+        params.push(ast_func::Param::new(name, param.span()));
 
         if ctx.skip_punct(PunctKind::Comma) {
             continue;
         }
 
-        ctx.expect_close_delim(DelimKind::Paren)?;
+        ctx.expect_close_delim(DelimKind::Paren)
+            .context("after parameter")?;
 
         return Ok(params);
     }
@@ -100,4 +108,23 @@ mod test {
         let mut ctx = Context::new(tokenize(&source));
         insta::assert_debug_snapshot!(parse(&mut ctx).expect("fail to parse"));
     }
+
+    #[test]
+    fn parse7() {
+        let source = Source::String(r#"func f(x) x;;"#.to_string());
+        let mut ctx = Context::new(tokenize(&source));
+        let err = parse(&mut ctx).unwrap_err();
+        let diag = crate::diagnostic(&err).unwrap();
+        assert_eq!(diag.message(), "expected `:` after function parameters");
+    }
+
+    #[test]
+    fn parse8() {
+        let source = Source::String(r#"func f(a, a, c): c ;;"#.to_string());
+        let mut ctx = Context::new(tokenize(&source));
+        let err = parse(&mut ctx).unwrap_err();
+        let diag = crate::diagnostic(&err).unwrap();
+        assert_eq!(diag.message(), "duplicate parameter `a`");
+        assert_eq!(diag.span().map(|s| (s.start, s.end)), Some((10, 11)));
+    }
 }
@@ -1,95 +1,165 @@
 extern crate eb_ast as ast;
+extern crate eb_diagnostics as diagnostics;
 extern crate eb_lexer as lexer;
 
 pub mod expr;
 pub mod function;
 
 use anyhow::Result;
+use diagnostics::Diagnostic;
 use lexer::{
-    location::Location,
+    location::{FileId, Span},
     token::{DelimKind, PunctKind, Token, TokenKind, TokenStream},
 };
 use std::{error::Error as StdErr, fmt, iter::Peekable};
 
 #[derive(Debug)]
 pub enum Error {
-    ExpectedKeyword(Location, &'static str),
-    ExpectedAnyIdent(Location),
-    ExpectedOpenDelim(Location, DelimKind),
-    ExpectedCloseDelim(Location, DelimKind),
-    ExpectedPunct(Location, PunctKind),
-    ExpectedAny(Location, &'static str),
+    ExpectedKeyword(Span, &'static str),
+    ExpectedAnyIdent(Span),
+    ExpectedOpenDelim(Span, DelimKind),
+    ExpectedCloseDelim(Span, DelimKind),
+    ExpectedPunct(Span, PunctKind),
+    ExpectedAny(Span, &'static str),
+    InvalidInt(Span),
+    IntOutOfRange(Span),
+    InvalidAssignTarget(Span),
+    DuplicateParam(Span, String),
+    Lexer(lexer::Error),
     EOF,
 }
 
+/// Every error reported while parsing, in source order.
+#[derive(Debug)]
+pub struct Errors(pub Vec<anyhow::Error>);
+
 pub struct Context<'a> {
     tokens: Peekable<TokenStream<'a>>,
+    file: FileId,
+    prev_span: Option<Span>,
+    errors: Vec<anyhow::Error>,
 }
 
 impl<'a> Context<'a> {
     pub fn new(tokens: TokenStream<'a>) -> Self {
         Self {
+            file: tokens.file(),
             tokens: tokens.peekable(),
+            prev_span: None,
+            errors: vec![],
         }
     }
 
-    pub fn peek(&mut self) -> Option<&Token> {
+    /// Returns the file being parsed.
+    pub fn file(&self) -> FileId {
+        self.file
+    }
+
+    /// Records an error the parser recovered from.
+    pub fn report(&mut self, err: anyhow::Error) {
+        self.errors.push(err);
+    }
+
+    pub fn errors(&self) -> &[anyhow::Error] {
+        &self.errors
+    }
+
+    /// Returns the recorded errors as `Errors`, if there are any.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.errors.is_empty() {
+            return Ok(());
+        }
+        Err(Errors(std::mem::take(&mut self.errors)).into())
+    }
+
+    /// Skips tokens up to the next `;`, `;;`, `func`, `if`, `let`, `while` or `for`, where parsing
+    /// can resume after an error.
+    pub fn synchronize(&mut self) {
+        while let Some(tok) = self.peek() {
+            match tok.kind() {
+                TokenKind::Punct(PunctKind::Semicolon | PunctKind::DoubleSemicolon) => break,
+                TokenKind::Ident("func" | "if" | "let" | "while" | "for") => break,
+                _ => {
+                    self.next();
+                }
+            }
+        }
+    }
+
+    pub fn peek(&mut self) -> Option<&Token<'a>> {
         self.tokens.peek()
     }
 
-    pub fn next(&mut self) -> Option<Token> {
-        self.tokens.next()
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Option<Token<'a>> {
+        let tok = self.tokens.next();
+        if let Some(tok) = &tok {
+            self.prev_span = Some(tok.span());
+        }
+        tok
+    }
+
+    pub fn cur_span(&mut self) -> Result<Span> {
+        self.peek().map_or(Err(Error::EOF.into()), |t| Ok(t.span()))
+    }
+
+    pub fn prev_span(&self) -> Option<Span> {
+        self.prev_span
     }
 
-    pub fn cur_loc(&mut self) -> Result<Location> {
-        self.peek().map_or(Err(Error::EOF.into()), |t| Ok(*t.loc()))
+    /// Returns the span from `start` to the end of the last consumed token.
+    pub fn span_from(&self, start: Span) -> Span {
+        self.prev_span.map_or(start, |prev| start.merge(prev))
     }
 
-    pub fn expect_keyword(&mut self, kwd: &'static str) -> Result<Token> {
+    pub fn expect_keyword(&mut self, kwd: &'static str) -> Result<Token<'a>> {
         if let Some(tok) = self.peek() {
             return match tok.kind() {
                 TokenKind::Ident(i) if i == &kwd => Ok(self.next().unwrap()),
-                _ => Err(Error::ExpectedKeyword(*tok.loc(), kwd).into()),
+                _ => Err(unexpected(tok, Error::ExpectedKeyword(tok.span(), kwd))),
             };
         }
         Err(Error::EOF.into())
     }
 
-    pub fn expect_any_ident(&mut self) -> Result<Token> {
+    pub fn expect_any_ident(&mut self) -> Result<Token<'a>> {
         if let Some(tok) = self.peek() {
             return match tok.kind() {
                 TokenKind::Ident(_) => Ok(self.next().unwrap()),
-                _ => Err(Error::ExpectedAnyIdent(*tok.loc()).into()),
+                _ => Err(unexpected(tok, Error::ExpectedAnyIdent(tok.span()))),
             };
         }
         Err(Error::EOF.into())
     }
 
-    pub fn expect_open_delim(&mut self, delim: DelimKind) -> Result<Token> {
+    pub fn expect_open_delim(&mut self, delim: DelimKind) -> Result<Token<'a>> {
         if let Some(tok) = self.peek() {
             return match tok.kind() {
                 TokenKind::OpenDelim(d) if d == &delim => Ok(self.next().unwrap()),
-                _ => Err(Error::ExpectedOpenDelim(*tok.loc(), delim).into()),
+                _ => Err(unexpected(tok, Error::ExpectedOpenDelim(tok.span(), delim))),
             };
         }
         Err(Error::EOF.into())
     }
 
-    pub fn expect_close_delim(&mut self, delim: DelimKind) -> Result<Token> {
+    pub fn expect_close_delim(&mut self, delim: DelimKind) -> Result<Token<'a>> {
         match self.peek() {
             Some(tok) => match tok.kind() {
                 TokenKind::CloseDelim(d) if d == &delim => Ok(self.next().unwrap()),
-                _ => Err(Error::ExpectedCloseDelim(*tok.loc(), delim).into()),
+                _ => Err(unexpected(
+                    tok,
+                    Error::ExpectedCloseDelim(tok.span(), delim),
+                )),
             },
             None => Err(Error::EOF.into()),
         }
     }
 
-    pub fn expect_punct(&mut self, punct: PunctKind) -> Result<Token> {
+    pub fn expect_punct(&mut self, punct: PunctKind) -> Result<Token<'a>> {
         match self.peek() {
             Some(tok) => match tok.kind() {
                 TokenKind::Punct(p) if p == &punct => Ok(self.next().unwrap()),
-                _ => Err(Error::ExpectedPunct(*tok.loc(), punct).into()),
+                _ => Err(unexpected(tok, Error::ExpectedPunct(tok.span(), punct))),
             },
             None => Err(Error::EOF.into()),
         }
@@ -108,10 +178,97 @@ impl<'a> Context<'a> {
     }
 }
 
+impl Error {
+    /// Returns the span the error points at. `EOF` has none.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Self::ExpectedKeyword(span, _)
+            | Self::ExpectedAnyIdent(span)
+            | Self::ExpectedOpenDelim(span, _)
+            | Self::ExpectedCloseDelim(span, _)
+            | Self::ExpectedPunct(span, _)
+            | Self::ExpectedAny(span, _)
+            | Self::InvalidInt(span)
+            | Self::IntOutOfRange(span)
+            | Self::InvalidAssignTarget(span)
+            | Self::DuplicateParam(span, _) => Some(*span),
+            Self::Lexer(e) => Some(e.span()),
+            Self::EOF => None,
+        }
+    }
+
+    /// Converts the error into a diagnostic. `context` describes where the error happened, as in
+    /// "expected `:` *after function parameters*".
+    pub fn to_diagnostic(&self, context: Option<&str>) -> Diagnostic {
+        let message = match (self, context) {
+            (Self::Lexer(_), _) | (_, None) => self.to_string(),
+            (_, Some(context)) => format!("{} {}", self, context),
+        };
+        let diag = Diagnostic::error(message);
+        let diag = match self.span() {
+            Some(span) => diag.with_span(span),
+            None => diag,
+        };
+        match self {
+            Self::IntOutOfRange(_) => {
+                diag.with_note(format!("the largest integer is {}", i64::MAX))
+            }
+            Self::Lexer(lexer::Error::InvalidEscape(_)) => {
+                diag.with_help(r#"valid escapes are \n, \t, \", \\ and \u{...}"#)
+            }
+            Self::Lexer(lexer::Error::UnterminatedString(_)) => {
+                diag.with_label("string starts here")
+            }
+            _ => diag,
+        }
+    }
+}
+
+/// Converts an error returned by the parser into a diagnostic. The innermost context attached to
+/// it with `anyhow::Context` describes where the error happened. Returns `None` if `err` is not a
+/// parser error.
+pub fn diagnostic(err: &anyhow::Error) -> Option<Diagnostic> {
+    let root = err.downcast_ref::<Error>()?;
+    let context = err.chain().take_while(|e| !e.is::<Error>()).last();
+    Some(root.to_diagnostic(context.map(|c| c.to_string()).as_deref()))
+}
+
+/// Returns `err`, or the lexer error `tok` carries if it is an error token.
+pub fn unexpected(tok: &Token, err: Error) -> anyhow::Error {
+    match tok.kind() {
+        TokenKind::Error(e) => Error::Lexer(e.clone()).into(),
+        _ => err.into(),
+    }
+}
+
 impl StdErr for Error {}
 
+impl fmt::Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.as_slice() {
+            [err] => write!(f, "{:#}", err),
+            errs => write!(f, "{} syntax errors", errs.len()),
+        }
+    }
+}
+
+impl StdErr for Errors {}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Self::ExpectedKeyword(_, kwd) => write!(f, "expected `{}`", kwd),
+            Self::ExpectedAnyIdent(_) => write!(f, "expected identifier"),
+            Self::ExpectedOpenDelim(_, delim) => write!(f, "expected `{}`", delim.open_str()),
+            Self::ExpectedCloseDelim(_, delim) => write!(f, "expected `{}`", delim.close_str()),
+            Self::ExpectedPunct(_, punct) => write!(f, "expected `{}`", punct.as_str()),
+            Self::ExpectedAny(_, what) => write!(f, "expected {}", what),
+            Self::InvalidInt(_) => write!(f, "invalid integer literal"),
+            Self::IntOutOfRange(_) => write!(f, "integer literal is too large"),
+            Self::InvalidAssignTarget(_) => write!(f, "invalid left-hand side of assignment"),
+            Self::DuplicateParam(_, name) => write!(f, "duplicate parameter `{}`", name),
+            Self::Lexer(e) => write!(f, "{}", e),
+            Self::EOF => write!(f, "unexpected end of file"),
+        }
     }
 }
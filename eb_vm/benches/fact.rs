@@ -0,0 +1,39 @@
+extern crate criterion;
+extern crate eb_codegen_fast as codegen;
+extern crate eb_lexer as lexer;
+extern crate eb_parser as parser;
+extern crate eb_vm as vm;
+extern crate eb_vm_ctx as vm_ctx;
+
+use codegen::expr::visit;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use lexer::{source::Source, tokenize};
+use parser::{expr::parse_program, Context as ParserContext};
+use vm::VM;
+use vm_ctx::FunctionContext;
+
+fn compile(src: &str) -> FunctionContext {
+    let source = Source::String(src.to_string());
+    let mut ctx = ParserContext::new(tokenize(&source));
+    let node = parse_program(&mut ctx).expect("fail to parse");
+    let mut func = FunctionContext::default();
+    visit(&mut func, &node).unwrap();
+    func
+}
+
+/// The recursive factorial of `vm1`, which calls a function once per level.
+fn fact(c: &mut Criterion) {
+    let func = compile(
+        r#"
+        func fact(x):
+            if x == 1:
+                return 1 ;;
+            x * fact(x - 1) ;;
+        fact(20)"#,
+    );
+    let mut vm = VM::default();
+    c.bench_function("fact 20", |b| b.iter(|| vm.run(black_box(&func)).unwrap()));
+}
+
+criterion_group!(benches, fact);
+criterion_main!(benches);
@@ -0,0 +1,349 @@
+//! Conversions between values and Rust types, for the arguments and results of native functions
+//! and of calls from Rust.
+
+use super::{ErrorKind, RuntimeError};
+use std::{convert::TryFrom, error::Error as StdErr, fmt, rc::Rc};
+use vm_ctx::value::{UserData, Value};
+
+/// A Rust type a value converts into.
+pub trait FromValue: Sized {
+    fn from_value(val: Value) -> Result<Self, ConversionError>;
+}
+
+/// A Rust type that converts into a value.
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+/// The arguments of a call from Rust: a tuple of types that convert into values.
+pub trait IntoArgs {
+    fn into_args(self) -> Vec<Value>;
+}
+
+/// Why a value does not convert into a Rust type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConversionError {
+    /// The value is of type `found`, which does not convert.
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// An `Int` does not fit in the integer type `ty`.
+    OutOfRange { value: i64, ty: &'static str },
+    /// A list converted into a tuple has the wrong number of elements.
+    LengthMismatch { expected: usize, found: usize },
+}
+
+impl ConversionError {
+    fn mismatch(expected: &'static str, val: &Value) -> Self {
+        Self::TypeMismatch {
+            expected,
+            found: val.type_name(),
+        }
+    }
+}
+
+impl StdErr for ConversionError {}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TypeMismatch { expected, found } => {
+                write!(f, "expected `{}` value, found `{}`", expected, found)
+            }
+            Self::OutOfRange { value, ty } => write!(f, "{} is out of range for `{}`", value, ty),
+            Self::LengthMismatch { expected, found } => write!(
+                f,
+                "expected a list of {} element(s), found {}",
+                expected, found
+            ),
+        }
+    }
+}
+
+impl From<ConversionError> for RuntimeError {
+    fn from(err: ConversionError) -> Self {
+        Self {
+            conversion: Some(err.clone()),
+            ..Self::new(ErrorKind::Conversion, err.to_string())
+        }
+    }
+}
+
+impl FromValue for Value {
+    fn from_value(val: Value) -> Result<Self, ConversionError> {
+        Ok(val)
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+/// Userdata of any type. Its data is downcast with `UserData::borrow`.
+impl FromValue for Rc<UserData> {
+    fn from_value(val: Value) -> Result<Self, ConversionError> {
+        match val {
+            Value::UserData(data) => Ok(data),
+            val => Err(ConversionError::mismatch("userdata", &val)),
+        }
+    }
+}
+
+impl IntoValue for Rc<UserData> {
+    fn into_value(self) -> Value {
+        Value::UserData(self)
+    }
+}
+
+/// Implements the conversion into the integer type `$ty`, which fails for values out of its range.
+macro_rules! impl_from_int {
+    ($($ty:ty),*) => {
+        $(
+            impl FromValue for $ty {
+                fn from_value(val: Value) -> Result<Self, ConversionError> {
+                    match val {
+                        Value::Int(i) => <$ty>::try_from(i).map_err(|_| {
+                            ConversionError::OutOfRange {
+                                value: i,
+                                ty: stringify!($ty),
+                            }
+                        }),
+                        val => Err(ConversionError::mismatch("int", &val)),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+/// Implements the conversion from the integer type `$ty`, whose values all fit in an `i64`.
+/// `u64` and `usize` do not, so they only convert from values.
+macro_rules! impl_into_int {
+    ($($ty:ty),*) => {
+        $(
+            impl IntoValue for $ty {
+                fn into_value(self) -> Value {
+                    Value::Int(self as i64)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_int!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+impl_into_int!(i8, i16, i32, i64, isize, u8, u16, u32);
+
+/// An `Int` is promoted, as in arithmetic.
+impl FromValue for f64 {
+    fn from_value(val: Value) -> Result<Self, ConversionError> {
+        match val {
+            Value::Float(f) => Ok(f),
+            Value::Int(i) => Ok(i as f64),
+            val => Err(ConversionError::mismatch("float", &val)),
+        }
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self) -> Value {
+        Value::Float(self)
+    }
+}
+
+impl FromValue for f32 {
+    fn from_value(val: Value) -> Result<Self, ConversionError> {
+        f64::from_value(val).map(|f| f as f32)
+    }
+}
+
+impl IntoValue for f32 {
+    fn into_value(self) -> Value {
+        Value::Float(self as f64)
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(val: Value) -> Result<Self, ConversionError> {
+        match val {
+            Value::Bool(b) => Ok(b),
+            val => Err(ConversionError::mismatch("bool", &val)),
+        }
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl FromValue for String {
+    fn from_value(val: Value) -> Result<Self, ConversionError> {
+        match val {
+            Value::String(s) => Ok(s),
+            val => Err(ConversionError::mismatch("string", &val)),
+        }
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self) -> Value {
+        Value::String(self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self) -> Value {
+        Value::String(self.to_owned())
+    }
+}
+
+/// `()` stands for `nil`.
+impl FromValue for () {
+    fn from_value(val: Value) -> Result<Self, ConversionError> {
+        match val {
+            Value::Nil => Ok(()),
+            val => Err(ConversionError::mismatch("nil", &val)),
+        }
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::Nil
+    }
+}
+
+/// `None` stands for `nil`.
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(val: Value) -> Result<Self, ConversionError> {
+        match val {
+            Value::Nil => Ok(None),
+            val => T::from_value(val).map(Some),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        self.map_or(Value::Nil, IntoValue::into_value)
+    }
+}
+
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(val: Value) -> Result<Self, ConversionError> {
+        match val {
+            Value::List(elems) => elems.iter().cloned().map(T::from_value).collect(),
+            val => Err(ConversionError::mismatch("list", &val)),
+        }
+    }
+}
+
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self) -> Value {
+        Value::List(Rc::new(
+            self.into_iter().map(IntoValue::into_value).collect(),
+        ))
+    }
+}
+
+/// Implements the conversions of the tuple of `$elem`, which is a list with an element for each.
+/// Tuples are also the arguments of calls from Rust.
+macro_rules! impl_tuple {
+    ($len:expr; $($elem:ident),*) => {
+        impl<$($elem: FromValue),*> FromValue for ($($elem,)*) {
+            fn from_value(val: Value) -> Result<Self, ConversionError> {
+                let elems = match val {
+                    Value::List(elems) => elems,
+                    val => return Err(ConversionError::mismatch("list", &val)),
+                };
+                if elems.len() != $len {
+                    return Err(ConversionError::LengthMismatch {
+                        expected: $len,
+                        found: elems.len(),
+                    });
+                }
+                let mut elems = elems.iter().cloned();
+                Ok(($($elem::from_value(elems.next().unwrap())?,)*))
+            }
+        }
+
+        impl<$($elem: IntoValue),*> IntoValue for ($($elem,)*) {
+            #[allow(non_snake_case)]
+            fn into_value(self) -> Value {
+                let ($($elem,)*) = self;
+                Value::List(Rc::new(vec![$($elem.into_value()),*]))
+            }
+        }
+
+        impl<$($elem: IntoValue),*> IntoArgs for ($($elem,)*) {
+            #[allow(non_snake_case)]
+            fn into_args(self) -> Vec<Value> {
+                let ($($elem,)*) = self;
+                vec![$($elem.into_value()),*]
+            }
+        }
+    };
+}
+
+impl_tuple!(1; A);
+impl_tuple!(2; A, B);
+impl_tuple!(3; A, B, C);
+impl_tuple!(4; A, B, C, D);
+
+impl IntoArgs for () {
+    fn into_args(self) -> Vec<Value> {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn convert1() {
+        assert_eq!(u8::from_value(Value::Int(255)), Ok(255));
+        assert_eq!(
+            u8::from_value(Value::Int(-1)),
+            Err(ConversionError::OutOfRange {
+                value: -1,
+                ty: "u8"
+            })
+        );
+        assert_eq!(u64::from_value(Value::Int(i64::MAX)), Ok(i64::MAX as u64));
+        assert_eq!(
+            usize::from_value(Value::Int(-1)),
+            Err(ConversionError::OutOfRange {
+                value: -1,
+                ty: "usize"
+            })
+        );
+        assert_eq!(f32::from_value(Value::Int(2)), Ok(2.0));
+        assert_eq!(
+            bool::from_value(Value::Nil),
+            Err(ConversionError::TypeMismatch {
+                expected: "bool",
+                found: "nil"
+            })
+        );
+        assert_eq!(Option::<bool>::from_value(Value::Nil), Ok(None));
+
+        let val = (1, "a", vec![true], Some(())).into_value();
+        assert_eq!(val.to_string(), "[1, a, [true], nil]");
+        assert_eq!(
+            <(i32, String, Vec<bool>, Option<()>)>::from_value(val.clone()),
+            Ok((1, "a".to_owned(), vec![true], None))
+        );
+        assert_eq!(
+            <(i32, String)>::from_value(val),
+            Err(ConversionError::LengthMismatch {
+                expected: 2,
+                found: 4
+            })
+        );
+    }
+}
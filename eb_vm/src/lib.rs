@@ -1,179 +1,1869 @@
+extern crate eb_lexer as lexer;
 extern crate eb_vm_ctx as vm_ctx;
 extern crate rustc_hash;
 
+pub mod convert;
+pub mod native;
+pub mod userdata;
+
+use convert::{ConversionError, FromValue, IntoArgs};
+use lexer::location::Span;
+use native::IntoNative;
 use rustc_hash::FxHashMap;
+use std::{
+    any::{Any, TypeId},
+    cell::RefCell,
+    cmp::Ordering,
+    convert::TryFrom,
+    error::Error as StdErr,
+    fmt,
+    rc::Rc,
+};
+use userdata::TypeBuilder;
 use vm_ctx::inst::Inst;
-use vm_ctx::value::Value;
+use vm_ctx::value::{Closure, NativeFunc, UserData, UserType, Value};
 use vm_ctx::FunctionContext;
 
+/// An error that aborts `VM::run`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    kind: ErrorKind,
+    message: String,
+    /// Index of the failing instruction in the code of the innermost running function.
+    inst: usize,
+    /// The calls that were running, innermost first.
+    trace: Vec<TraceFrame>,
+    /// Why a value did not convert, for errors of kind `Conversion`.
+    conversion: Option<ConversionError>,
+}
+
+/// A call that was running when an error occurred.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// Name of the called function, empty for top-level code.
+    pub func: String,
+    /// Where the function was executing: the failing expression for the innermost call, and the
+    /// pending call for the others. `None` for code compiled without a line table.
+    pub span: Option<Span>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// A variable that is not defined was used.
+    UndefinedName,
+    /// A value of the wrong type was given to an operation.
+    TypeError,
+    /// A function was called with the wrong number of arguments.
+    ArityMismatch,
+    /// A value passed to Rust does not convert into the Rust type, as returned by
+    /// `RuntimeError::conversion`.
+    Conversion,
+    DivisionByZero,
+    /// The result of integer arithmetic does not fit in an `Int`.
+    Overflow,
+    IndexOutOfRange,
+    /// More than `MAX_FRAMES` calls were running at once, such as with unbounded recursion.
+    StackOverflow,
+    /// The code is malformed, such as popping more values than it pushed. The compiler never
+    /// generates such code.
+    InvalidCode,
+    /// A native function failed with an error of its own.
+    Native,
+}
+
+/// The maximum number of calls running at once in a `VM::run`, including the top-level code.
+pub const MAX_FRAMES: usize = 1000;
+
+/// The functions running in a `VM::run`, innermost last. Each field has an entry per frame.
+#[derive(Default)]
+struct CallStack {
+    funcs: Vec<Rc<FunctionContext>>,
+    /// The function each frame runs, as it was called. It is a closure for nested functions.
+    callees: Vec<Value>,
+    pcs: Vec<usize>,
+    /// Where each frame starts on the value stack. Its locals are stored from there, and the whole
+    /// frame is dropped on return.
+    bases: Vec<usize>,
+    /// The variables each frame shares with closures.
+    upvalues: Vec<Vec<Rc<RefCell<Value>>>>,
+}
+
+#[derive(Default)]
 pub struct VM {
+    /// Holds the locals of every running function, each followed by its temporaries.
     pub stack: Vec<Value>,
-    pub env: Vec<FxHashMap<String, Value>>,
+    /// Global variables. They outlive a single `run` so that top-level definitions stay visible
+    /// to the code run afterwards.
+    pub globals: FxHashMap<String, Value>,
+    /// Registered host types.
+    types: FxHashMap<TypeId, Rc<UserType>>,
 }
 
-impl Default for VM {
-    fn default() -> Self {
-        Self {
-            stack: vec![],
-            env: vec![],
-        }
+impl VM {
+    /// Runs `ctx` and returns the value of its code. On error, the stack is restored to the state
+    /// before the call, so the VM can be used again.
+    pub fn run(&mut self, ctx: &FunctionContext) -> Result<Value, RuntimeError> {
+        let base = self.stack.len();
+        self.stack.resize(base + ctx.locals.len(), Value::Nil);
+        // Only the top-level code is copied, once per `run`.
+        self.start(Rc::new(ctx.clone()), base)
     }
-}
 
-impl VM {
-    fn lookup(&mut self, s: &str) -> Option<&Value> {
-        for e in self.env.iter().rev() {
-            if let Some(v) = e.get(s) {
-                return Some(v);
+    /// Calls the global function `name` with `args`, a tuple such as `(1, "x")`, and converts its
+    /// result into `R`.
+    pub fn call<A, R>(&mut self, name: &str, args: A) -> Result<R, RuntimeError>
+    where
+        A: IntoArgs,
+        R: FromValue,
+    {
+        let func = self
+            .globals
+            .get(name)
+            .ok_or_else(|| RuntimeError::undefined(name))?
+            .clone();
+        self.call_value(func, args)
+    }
+
+    /// Calls `func`, such as a function a script passed to a native function, with `args`.
+    pub fn call_value<A, R>(&mut self, func: Value, args: A) -> Result<R, RuntimeError>
+    where
+        A: IntoArgs,
+        R: FromValue,
+    {
+        let args = args.into_args();
+        let base = self.stack.len();
+        let mut caller = FunctionContext::default();
+        caller.push(Inst::Call(args.len()));
+        self.stack.extend(args);
+        self.stack.push(func);
+        let val = self.start(Rc::new(caller), base).map_err(|mut err| {
+            // The caller stands for the host.
+            err.trace.pop();
+            err
+        })?;
+        Ok(R::from_value(val)?)
+    }
+
+    /// Returns the value of the global variable `name` converted into `T`.
+    pub fn get_global<T: FromValue>(&self, name: &str) -> Result<T, RuntimeError> {
+        let val = self
+            .globals
+            .get(name)
+            .ok_or_else(|| RuntimeError::undefined(name))?;
+        Ok(T::from_value(val.clone())?)
+    }
+
+    /// Runs `func` in a new frame starting at `base` on the stack, and returns its value. On
+    /// error, the stack is truncated to `base`.
+    fn start(&mut self, func: Rc<FunctionContext>, base: usize) -> Result<Value, RuntimeError> {
+        let mut calls = CallStack {
+            callees: vec![Value::Func(func.clone())],
+            funcs: vec![func],
+            pcs: vec![0],
+            bases: vec![base],
+            upvalues: vec![vec![]],
+        };
+        match self.exec(&mut calls) {
+            Ok(()) => Ok(self.stack.pop().unwrap()),
+            Err(err) => {
+                self.stack.truncate(base);
+                Err(RuntimeError {
+                    inst: *calls.pcs.last().unwrap(),
+                    trace: calls.trace(),
+                    ..err
+                })
             }
         }
-        None
     }
 
-    pub fn run(&mut self, ctx: &FunctionContext) {
-        self.env.push({
-            let mut map = FxHashMap::default();
-            for child in &ctx.children {
-                map.insert(child.name.clone(), Value::Func(Box::new(child.clone())));
-            }
-            map
-        });
+    /// Makes `func` callable from scripts as the global `name`. It is either a closure taking the
+    /// arguments as `&[Value]` and returning `Result<Value, E>`, or a function taking and returning
+    /// types that convert from and into values, such as `fn(i64, i64) -> i64`.
+    pub fn register_fn<F, Args>(&mut self, name: &str, func: F)
+    where
+        F: IntoNative<Args>,
+    {
+        let native = NativeFunc {
+            name: name.to_owned(),
+            arity: F::arity(),
+            func: func.into_native(),
+        };
+        self.globals
+            .insert(name.to_owned(), Value::NativeFunc(Rc::new(native)));
+    }
+
+    /// Registers the host type `T`, so that its values can be handed to scripts.
+    pub fn register_type<T: Any>(&mut self, ty: TypeBuilder<T>) {
+        self.types.insert(TypeId::of::<T>(), Rc::new(ty.build()));
+    }
+
+    /// Wraps `data` into a value scripts can use through the fields and methods of its type.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the type of `data` is not registered.
+    pub fn userdata<T: Any>(&self, data: T) -> Value {
+        let ty = match self.types.get(&TypeId::of::<T>()) {
+            Some(ty) => ty.clone(),
+            None => panic!("type `{}` is not registered", std::any::type_name::<T>()),
+        };
+        Value::UserData(Rc::new(UserData::new(ty, Box::new(data))))
+    }
+
+    /// Calls `native` with the top `args` values on the stack, and replaces them with its result.
+    fn call_native(&mut self, native: &NativeFunc, args: usize) -> Result<(), RuntimeError> {
+        if let Some(arity) = native.arity.filter(|arity| *arity != args) {
+            return Err(RuntimeError::arity(&native.name, arity, args));
+        }
+        let args = self.stack.split_off(self.stack.len() - args);
+        let val = (native.func)(&args).map_err(|err| match err.downcast::<RuntimeError>() {
+            Ok(err) => *err,
+            Err(err) => RuntimeError::new(ErrorKind::Native, err.to_string()),
+        })?;
+        self.stack.push(val);
+        Ok(())
+    }
+
+    /// Checks that the innermost frame, whose temporaries start at `floor`, has at least `n` of
+    /// them.
+    fn ensure(&self, floor: usize, n: usize) -> Result<(), RuntimeError> {
+        if self.stack.len() < floor + n {
+            return Err(RuntimeError::invalid_code("stack underflow"));
+        }
+        Ok(())
+    }
+
+    /// Pops the top temporary of the innermost frame.
+    fn pop(&mut self, floor: usize) -> Result<Value, RuntimeError> {
+        self.ensure(floor, 1)?;
+        Ok(self.stack.pop().unwrap())
+    }
+
+    /// Returns the top temporary of the innermost frame.
+    fn peek(&self, floor: usize) -> Result<&Value, RuntimeError> {
+        self.ensure(floor, 1)?;
+        Ok(self.stack.last().unwrap())
+    }
+
+    fn exec(&mut self, calls: &mut CallStack) -> Result<(), RuntimeError> {
+        for child in &calls.funcs[0].children {
+            self.globals
+                .insert(child.name.clone(), Value::Func(child.clone()));
+        }
 
-        let mut pc_stack = vec![0];
-        let mut code_stack = vec![ctx.code.0.clone()];
         loop {
-            if *pc_stack.last().unwrap() >= code_stack.last().unwrap().len() {
-                self.env.pop().unwrap();
-                code_stack.pop();
-                pc_stack.pop();
-                if self.env.len() == 1 {
-                    break;
-                }
+            if calls.funcs.is_empty() {
+                break;
             }
-            let inst = &code_stack.last().unwrap()[*pc_stack.last().unwrap()];
+            // Running off the end of the code returns.
+            let inst = calls
+                .funcs
+                .last()
+                .unwrap()
+                .code
+                .0
+                .get(*calls.pcs.last().unwrap())
+                .unwrap_or(&Inst::Ret);
+            let floor = calls.floor();
             match inst {
                 Inst::PushInt(i) => {
                     self.stack.push(Value::Int(*i));
-                    *pc_stack.last_mut().unwrap() += 1;
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::PushFloat(f) => {
+                    self.stack.push(Value::Float(*f));
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::PushBool(b) => {
+                    self.stack.push(Value::Bool(*b));
+                    *calls.pcs.last_mut().unwrap() += 1;
                 }
                 Inst::PushStr(s) => {
                     self.stack.push(Value::String(s.clone()));
-                    *pc_stack.last_mut().unwrap() += 1;
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::PushNil => {
+                    self.stack.push(Value::Nil);
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::Pop => {
+                    self.pop(floor)?;
+                    *calls.pcs.last_mut().unwrap() += 1;
                 }
                 Inst::Get(s) => {
-                    let val = self.lookup(s).unwrap().clone();
-                    self.stack.push(val.clone());
-                    *pc_stack.last_mut().unwrap() += 1;
-                }
-                Inst::Call => {
-                    *pc_stack.last_mut().unwrap() += 1;
-                    let callee = self.stack.pop().unwrap();
-                    match callee {
-                        Value::Func(func) => {
-                            let mut args = vec![];
-                            for _ in 0..func.param_names.len() {
-                                args.push(self.stack.pop().unwrap());
-                            }
-                            self.env.push({
-                                let mut map = FxHashMap::default();
-                                for child in &func.children {
-                                    map.insert(
-                                        child.name.clone(),
-                                        Value::Func(Box::new(child.clone())),
-                                    );
-                                }
-                                for (param, val) in
-                                    func.param_names.iter().zip(args.into_iter().rev())
-                                {
-                                    map.insert(param.clone(), val);
-                                }
-                                map
-                            });
-                            pc_stack.push(0);
-                            code_stack.push(func.code.0.clone());
+                    let val = self
+                        .globals
+                        .get(s)
+                        .ok_or_else(|| RuntimeError::undefined(s))?
+                        .clone();
+                    self.stack.push(val);
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::Define(s) => {
+                    let val = self.peek(floor)?.clone();
+                    self.globals.insert(s.clone(), val);
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::Set(s) => {
+                    let val = self.peek(floor)?.clone();
+                    *self
+                        .globals
+                        .get_mut(s)
+                        .ok_or_else(|| RuntimeError::undefined(s))? = val;
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::GetLocal(slot) => {
+                    let val = self.stack[calls.local(*slot)?].clone();
+                    self.stack.push(val);
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::SetLocal(slot) => {
+                    let val = self.peek(floor)?.clone();
+                    let slot = calls.local(*slot)?;
+                    self.stack[slot] = val;
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::GetUpvalue(idx) => {
+                    let val = calls.upvalue(*idx)?.borrow().clone();
+                    self.stack.push(val);
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::SetUpvalue(idx) => {
+                    let val = self.peek(floor)?.clone();
+                    *calls.upvalue(*idx)?.borrow_mut() = val;
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::MakeClosure(idx) => {
+                    let func = calls
+                        .funcs
+                        .last()
+                        .unwrap()
+                        .children
+                        .get(*idx)
+                        .ok_or_else(|| {
+                            RuntimeError::invalid_code(format!("function {} does not exist", idx))
+                        })?
+                        .clone();
+                    let upvalues = func
+                        .captures
+                        .iter()
+                        .map(|i| calls.upvalue(*i).map(Rc::clone))
+                        .collect::<Result<_, _>>()?;
+                    self.stack
+                        .push(Value::Closure(Rc::new(Closure { func, upvalues })));
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::GetCallee => {
+                    let callee = calls.callees.last().unwrap().clone();
+                    self.stack.push(callee);
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::Call(args) => {
+                    // The arguments are below the callee.
+                    self.ensure(floor, args + 1)?;
+                    let callee = self.pop(floor)?;
+                    let (func, mut upvalues) = match &callee {
+                        Value::Func(func) => (func.clone(), vec![]),
+                        Value::Closure(closure) => (closure.func.clone(), closure.upvalues.clone()),
+                        Value::NativeFunc(native) => {
+                            self.call_native(native, *args)?;
+                            *calls.pcs.last_mut().unwrap() += 1;
                             continue;
                         }
-                        _ => todo!(),
+                        _ => return Err(RuntimeError::not_callable(callee.type_name())),
+                    };
+                    if *args != func.param_names.len() {
+                        return Err(RuntimeError::arity(
+                            &func.name,
+                            func.param_names.len(),
+                            *args,
+                        ));
+                    }
+                    if calls.funcs.len() == MAX_FRAMES {
+                        return Err(RuntimeError::new(
+                            ErrorKind::StackOverflow,
+                            format!("maximum call depth of {} exceeded", MAX_FRAMES),
+                        ));
                     }
+                    *calls.pcs.last_mut().unwrap() += 1;
+                    upvalues.extend(
+                        (0..func.captured_locals).map(|_| Rc::new(RefCell::new(Value::Nil))),
+                    );
+                    // The arguments become the first locals of the new frame.
+                    let base = self.stack.len() - func.param_names.len();
+                    self.stack.resize(base + func.locals.len(), Value::Nil);
+                    calls.pcs.push(0);
+                    calls.funcs.push(func);
+                    calls.callees.push(callee);
+                    calls.bases.push(base);
+                    calls.upvalues.push(upvalues);
+                    continue;
+                }
+                Inst::MakeList(len) => {
+                    self.ensure(floor, *len)?;
+                    let elems = self.stack.split_off(self.stack.len() - len);
+                    self.stack.push(Value::List(Rc::new(elems)));
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::Index => {
+                    let index = self.pop(floor)?;
+                    let base = self.pop(floor)?;
+                    self.stack.push(index_value(base, index)?);
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::GetField(field) => {
+                    let base = self.pop(floor)?;
+                    self.stack.push(get_field(base, field)?);
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::Add => {
+                    let rhs = self.pop(floor)?;
+                    let lhs = self.pop(floor)?;
+                    let val = match (lhs, rhs) {
+                        (Value::String(lhs), Value::String(rhs)) => Value::String(lhs + &rhs),
+                        (lhs, rhs) => arith("+", lhs, rhs, i64::checked_add, |l, r| l + r)?,
+                    };
+                    self.stack.push(val);
+                    *calls.pcs.last_mut().unwrap() += 1;
                 }
                 Inst::Sub => {
-                    let rhs = self.stack.pop().unwrap();
-                    let lhs = self.stack.pop().unwrap();
-                    match (lhs, rhs) {
-                        (Value::Int(lhs), Value::Int(rhs)) => {
-                            self.stack.push(Value::Int(lhs - rhs));
-                        }
-                        _ => todo!(),
-                    }
-                    *pc_stack.last_mut().unwrap() += 1;
+                    let rhs = self.pop(floor)?;
+                    let lhs = self.pop(floor)?;
+                    self.stack
+                        .push(arith("-", lhs, rhs, i64::checked_sub, |l, r| l - r)?);
+                    *calls.pcs.last_mut().unwrap() += 1;
                 }
                 Inst::Mul => {
-                    let rhs = self.stack.pop().unwrap();
-                    let lhs = self.stack.pop().unwrap();
-                    match (lhs, rhs) {
-                        (Value::Int(lhs), Value::Int(rhs)) => {
-                            self.stack.push(Value::Int(lhs * rhs));
-                        }
-                        _ => todo!(),
+                    let rhs = self.pop(floor)?;
+                    let lhs = self.pop(floor)?;
+                    self.stack
+                        .push(arith("*", lhs, rhs, i64::checked_mul, |l, r| l * r)?);
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::Div => {
+                    let rhs = self.pop(floor)?;
+                    let lhs = self.pop(floor)?;
+                    if matches!(rhs, Value::Int(0)) || matches!(rhs, Value::Float(f) if f == 0.0) {
+                        return Err(RuntimeError::new(
+                            ErrorKind::DivisionByZero,
+                            "division by zero",
+                        ));
+                    }
+                    self.stack
+                        .push(arith("/", lhs, rhs, i64::checked_div, |l, r| l / r)?);
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::Pow => {
+                    let rhs = self.pop(floor)?;
+                    let lhs = self.pop(floor)?;
+                    // A negative power of zero divides by zero.
+                    let zero =
+                        matches!(lhs, Value::Int(0)) || matches!(lhs, Value::Float(f) if f == 0.0);
+                    if zero
+                        && (matches!(rhs, Value::Int(i) if i < 0)
+                            || matches!(rhs, Value::Float(f) if f < 0.0))
+                    {
+                        return Err(RuntimeError::new(
+                            ErrorKind::DivisionByZero,
+                            "division by zero",
+                        ));
                     }
-                    *pc_stack.last_mut().unwrap() += 1;
+                    let val = match (lhs, rhs) {
+                        // A negative exponent gives a fraction.
+                        (Value::Int(lhs), Value::Int(rhs)) if rhs < 0 => {
+                            Value::Float((lhs as f64).powf(rhs as f64))
+                        }
+                        (lhs, rhs) => arith("**", lhs, rhs, checked_pow, f64::powf)?,
+                    };
+                    self.stack.push(val);
+                    *calls.pcs.last_mut().unwrap() += 1;
                 }
                 Inst::Eq => {
-                    let rhs = self.stack.pop().unwrap();
-                    let lhs = self.stack.pop().unwrap();
-                    match (lhs, rhs) {
-                        (Value::Int(lhs), Value::Int(rhs)) => {
-                            self.stack.push(Value::Bool(lhs == rhs));
+                    let rhs = self.pop(floor)?;
+                    let lhs = self.pop(floor)?;
+                    self.stack.push(Value::Bool(equal(&lhs, &rhs)));
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::Neq => {
+                    let rhs = self.pop(floor)?;
+                    let lhs = self.pop(floor)?;
+                    self.stack.push(Value::Bool(!equal(&lhs, &rhs)));
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::Lt | Inst::Le | Inst::Gt | Inst::Ge => {
+                    let rhs = self.pop(floor)?;
+                    let lhs = self.pop(floor)?;
+                    let (op, pred): (_, fn(Ordering) -> bool) = match inst {
+                        Inst::Lt => ("<", Ordering::is_lt),
+                        Inst::Le => ("<=", Ordering::is_le),
+                        Inst::Gt => (">", Ordering::is_gt),
+                        _ => (">=", Ordering::is_ge),
+                    };
+                    self.stack.push(compare(op, lhs, rhs, pred)?);
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::Not => {
+                    let val = self.pop(floor)?;
+                    self.stack.push(Value::Bool(!truthy(&val)));
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::Neg => {
+                    let val = match self.pop(floor)? {
+                        Value::Int(i) => {
+                            Value::Int(i.checked_neg().ok_or_else(|| RuntimeError::overflow("-"))?)
                         }
-                        _ => todo!(),
-                    }
-                    *pc_stack.last_mut().unwrap() += 1;
+                        Value::Float(f) => Value::Float(-f),
+                        val => return Err(RuntimeError::unary_type_mismatch("-", val.type_name())),
+                    };
+                    self.stack.push(val);
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::Jmp(offset) => {
+                    jump(calls.pcs.last_mut().unwrap(), *offset);
                 }
                 Inst::Jne(offset) => {
-                    let val = self.stack.pop().unwrap();
-                    match val {
-                        Value::Bool(false) => {
-                            *pc_stack.last_mut().unwrap() += *offset as usize;
+                    let val = self.pop(floor)?;
+                    if truthy(&val) {
+                        *calls.pcs.last_mut().unwrap() += 1;
+                    } else {
+                        jump(calls.pcs.last_mut().unwrap(), *offset);
+                    }
+                }
+                Inst::JmpIfFalseOrPop(offset) | Inst::JmpIfTrueOrPop(offset) => {
+                    let jump_if = matches!(inst, Inst::JmpIfTrueOrPop(_));
+                    if truthy(self.peek(floor)?) == jump_if {
+                        jump(calls.pcs.last_mut().unwrap(), *offset);
+                    } else {
+                        self.stack.pop();
+                        *calls.pcs.last_mut().unwrap() += 1;
+                    }
+                }
+                Inst::Iter => {
+                    match self.peek(floor)? {
+                        Value::List(_) | Value::String(_) => self.stack.push(Value::Int(0)),
+                        val => {
+                            return Err(RuntimeError::new(
+                                ErrorKind::TypeError,
+                                format!("`{}` value is not iterable", val.type_name()),
+                            ))
+                        }
+                    }
+                    *calls.pcs.last_mut().unwrap() += 1;
+                }
+                Inst::IterNext(offset) => {
+                    self.ensure(floor, 2)?;
+                    let len = self.stack.len();
+                    // The state of a list is the index of its next element, and that of a string
+                    // is the byte offset of its next character.
+                    let mut step = 1;
+                    let next = match (&self.stack[len - 2], &self.stack[len - 1]) {
+                        (Value::List(elems), Value::Int(i)) => elems.get(*i as usize).cloned(),
+                        (Value::String(s), Value::Int(i)) => {
+                            let rest = s.get(*i as usize..).ok_or_else(|| {
+                                RuntimeError::invalid_code("invalid iteration state")
+                            })?;
+                            rest.chars().next().map(|c| {
+                                step = c.len_utf8() as i64;
+                                Value::String(c.to_string())
+                            })
                         }
-                        _ => {
-                            *pc_stack.last_mut().unwrap() += 1;
+                        _ => return Err(RuntimeError::invalid_code("no iteration in progress")),
+                    };
+                    match next {
+                        Some(val) => {
+                            if let Value::Int(i) = &mut self.stack[len - 1] {
+                                *i += step;
+                            }
+                            self.stack.push(val);
+                            *calls.pcs.last_mut().unwrap() += 1;
+                        }
+                        None => {
+                            self.stack.truncate(len - 2);
+                            jump(calls.pcs.last_mut().unwrap(), *offset);
                         }
                     }
                 }
                 Inst::Ret => {
-                    self.env.pop().unwrap();
-                    code_stack.pop();
-                    pc_stack.pop();
+                    let base = calls.bases.pop().unwrap();
+                    let val = if self.stack.len() > floor {
+                        self.pop(floor)?
+                    } else {
+                        Value::Nil
+                    };
+                    self.stack.truncate(base);
+                    self.stack.push(val);
+                    calls.funcs.pop();
+                    calls.callees.pop();
+                    calls.pcs.pop();
+                    calls.upvalues.pop();
                     continue;
                 }
             }
         }
+        Ok(())
+    }
+}
+
+impl CallStack {
+    /// Returns where the temporaries of the innermost frame start on the stack, after its locals.
+    fn floor(&self) -> usize {
+        self.bases.last().unwrap() + self.funcs.last().unwrap().locals.len()
+    }
+
+    /// Returns the position on the stack of the local in `slot` of the innermost frame.
+    fn local(&self, slot: u16) -> Result<usize, RuntimeError> {
+        if slot as usize >= self.funcs.last().unwrap().locals.len() {
+            return Err(RuntimeError::invalid_code(format!(
+                "local slot {} does not exist",
+                slot
+            )));
+        }
+        Ok(self.bases.last().unwrap() + slot as usize)
+    }
+
+    /// Returns the upvalue `idx` of the innermost frame.
+    fn upvalue(&self, idx: u16) -> Result<&Rc<RefCell<Value>>, RuntimeError> {
+        self.upvalues
+            .last()
+            .unwrap()
+            .get(idx as usize)
+            .ok_or_else(|| RuntimeError::invalid_code(format!("upvalue {} does not exist", idx)))
+    }
+
+    fn trace(&self) -> Vec<TraceFrame> {
+        let innermost = self.pcs.len() - 1;
+        self.funcs
+            .iter()
+            .zip(&self.pcs)
+            .enumerate()
+            .rev()
+            .map(|(i, (func, pc))| {
+                // Callers have already moved past their `Call`.
+                let pc = if i == innermost { *pc } else { pc - 1 };
+                TraceFrame {
+                    func: func.name.clone(),
+                    span: func.span(pc),
+                }
+            })
+            .collect()
+    }
+}
+
+impl RuntimeError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            inst: 0,
+            trace: vec![],
+            conversion: None,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Returns the index of the failing instruction.
+    pub fn inst(&self) -> usize {
+        self.inst
+    }
+
+    /// Returns the calls that were running, innermost first.
+    pub fn trace(&self) -> &[TraceFrame] {
+        &self.trace
+    }
+
+    /// Returns why a value did not convert, for errors of kind `Conversion`.
+    pub fn conversion(&self) -> Option<&ConversionError> {
+        self.conversion.as_ref()
+    }
+
+    fn undefined(name: &str) -> Self {
+        Self::new(
+            ErrorKind::UndefinedName,
+            format!("undefined variable `{}`", name),
+        )
+    }
+
+    /// The operator `op` does not accept operands of the given types.
+    fn type_mismatch(op: &str, lhs: &str, rhs: &str) -> Self {
+        Self::new(
+            ErrorKind::TypeError,
+            format!(
+                "unsupported operand types for `{}`: `{}` and `{}`",
+                op, lhs, rhs
+            ),
+        )
+    }
+
+    fn overflow(op: &str) -> Self {
+        Self::new(ErrorKind::Overflow, format!("integer overflow in `{}`", op))
+    }
+
+    fn unary_type_mismatch(op: &str, ty: &str) -> Self {
+        Self::new(
+            ErrorKind::TypeError,
+            format!("unsupported operand type for `{}`: `{}`", op, ty),
+        )
+    }
+
+    fn arity(name: &str, expected: usize, given: usize) -> Self {
+        Self::new(
+            ErrorKind::ArityMismatch,
+            format!(
+                "`{}` takes {} argument(s) but {} were given",
+                name, expected, given
+            ),
+        )
+    }
+
+    /// The data of a userdata value is in use by the host or another method call.
+    fn borrowed(ty: &str) -> Self {
+        Self::new(
+            ErrorKind::Native,
+            format!("`{}` value is already borrowed", ty),
+        )
+    }
+
+    fn not_callable(ty: &str) -> Self {
+        Self::new(
+            ErrorKind::TypeError,
+            format!("`{}` value is not callable", ty),
+        )
+    }
+
+    fn invalid_code(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::InvalidCode, message)
+    }
+
+    fn index_out_of_range(index: i64, len: usize) -> Self {
+        Self::new(
+            ErrorKind::IndexOutOfRange,
+            format!("index {} is out of range for length {}", index, len),
+        )
+    }
+}
+
+impl StdErr for RuntimeError {}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Applies the arithmetic operator `op`. `Int` with `Int` gives an `Int`, and an error if `int`
+/// overflows. If either operand is a `Float`, the other one is promoted to `Float` and so is the
+/// result.
+fn arith(
+    op: &'static str,
+    lhs: Value,
+    rhs: Value,
+    int: fn(i64, i64) -> Option<i64>,
+    float: fn(f64, f64) -> f64,
+) -> Result<Value, RuntimeError> {
+    match (lhs, rhs) {
+        (Value::Int(lhs), Value::Int(rhs)) => int(lhs, rhs)
+            .map(Value::Int)
+            .ok_or_else(|| RuntimeError::overflow(op)),
+        (Value::Float(lhs), Value::Float(rhs)) => Ok(Value::Float(float(lhs, rhs))),
+        (Value::Int(lhs), Value::Float(rhs)) => Ok(Value::Float(float(lhs as f64, rhs))),
+        (Value::Float(lhs), Value::Int(rhs)) => Ok(Value::Float(float(lhs, rhs as f64))),
+        (lhs, rhs) => Err(RuntimeError::type_mismatch(
+            op,
+            lhs.type_name(),
+            rhs.type_name(),
+        )),
+    }
+}
+
+/// Raises `base` to the non-negative `exp`, or returns `None` if the result overflows.
+fn checked_pow(base: i64, exp: i64) -> Option<i64> {
+    match u32::try_from(exp) {
+        Ok(exp) => base.checked_pow(exp),
+        // Only these bases stay in range for such large exponents.
+        Err(_) => match base {
+            0 | 1 => Some(base),
+            -1 => Some(if exp % 2 == 0 { 1 } else { -1 }),
+            _ => None,
+        },
+    }
+}
+
+/// Evaluates `base.field`. Only userdata has fields. A method gives a function bound to the
+/// receiver, so that `base.method(args...)` calls it.
+fn get_field(base: Value, field: &str) -> Result<Value, RuntimeError> {
+    if let Value::UserData(data) = &base {
+        if let Some(get) = data.ty.fields.get(field) {
+            let inner = data
+                .data
+                .try_borrow()
+                .map_err(|_| RuntimeError::borrowed(data.ty.name))?;
+            return Ok(get(&**inner));
+        }
+        if let Some(method) = data.ty.methods.get(field) {
+            let (receiver, func) = (data.clone(), method.func.clone());
+            let native = NativeFunc {
+                name: format!("{}.{}", data.ty.name, field),
+                arity: method.arity,
+                func: Box::new(move |args| {
+                    let mut inner = receiver
+                        .data
+                        .try_borrow_mut()
+                        .map_err(|_| RuntimeError::borrowed(receiver.ty.name))?;
+                    func(&mut **inner, args)
+                }),
+            };
+            return Ok(Value::NativeFunc(Rc::new(native)));
+        }
+    }
+    Err(RuntimeError::new(
+        ErrorKind::TypeError,
+        format!("`{}` value has no field `{}`", base.type_name(), field),
+    ))
+}
+
+/// Moves `pc` by `offset`, which is relative to the jump instruction.
+fn jump(pc: &mut usize, offset: i32) {
+    *pc = (*pc as isize + offset as isize) as usize;
+}
+
+/// Evaluates `base[index]`. Indexing a string gives its `index`th character, which takes time
+/// linear in `index`.
+fn index_value(base: Value, index: Value) -> Result<Value, RuntimeError> {
+    match (base, index) {
+        (Value::List(elems), Value::Int(i)) => usize::try_from(i)
+            .ok()
+            .and_then(|i| elems.get(i).cloned())
+            .ok_or_else(|| RuntimeError::index_out_of_range(i, elems.len())),
+        (Value::String(s), Value::Int(i)) => usize::try_from(i)
+            .ok()
+            .and_then(|i| s.chars().nth(i))
+            .map(|c| Value::String(c.to_string()))
+            .ok_or_else(|| RuntimeError::index_out_of_range(i, s.chars().count())),
+        (base, index) => Err(RuntimeError::type_mismatch(
+            "[]",
+            base.type_name(),
+            index.type_name(),
+        )),
+    }
+}
+
+/// Applies the relational operator `op`, which holds if `pred` accepts the ordering of the operands.
+/// Numbers are promoted as in `arith` and strings compare lexicographically. Comparisons with NaN
+/// are false.
+fn compare(
+    op: &'static str,
+    lhs: Value,
+    rhs: Value,
+    pred: fn(Ordering) -> bool,
+) -> Result<Value, RuntimeError> {
+    let ord = match (&lhs, &rhs) {
+        (Value::Int(lhs), Value::Int(rhs)) => lhs.partial_cmp(rhs),
+        (Value::Float(lhs), Value::Float(rhs)) => lhs.partial_cmp(rhs),
+        (Value::Int(lhs), Value::Float(rhs)) => (*lhs as f64).partial_cmp(rhs),
+        (Value::Float(lhs), Value::Int(rhs)) => lhs.partial_cmp(&(*rhs as f64)),
+        (Value::String(lhs), Value::String(rhs)) => lhs.partial_cmp(rhs),
+        _ => {
+            return Err(RuntimeError::type_mismatch(
+                op,
+                lhs.type_name(),
+                rhs.type_name(),
+            ))
+        }
+    };
+    Ok(Value::Bool(ord.is_some_and(pred)))
+}
+
+/// Returns whether `val` counts as true in a condition. Only `false` and `nil` do not.
+fn truthy(val: &Value) -> bool {
+    !matches!(val, Value::Bool(false) | Value::Nil)
+}
+
+/// Compares two values for equality. Numbers follow the promotion rule of `arith`, so `1 == 1.0`.
+/// Values of other different types are never equal, and functions are never equal to anything.
+fn equal(lhs: &Value, rhs: &Value) -> bool {
+    match (lhs, rhs) {
+        (Value::Int(lhs), Value::Int(rhs)) => lhs == rhs,
+        (Value::Float(lhs), Value::Float(rhs)) => lhs == rhs,
+        (Value::Int(lhs), Value::Float(rhs)) | (Value::Float(rhs), Value::Int(lhs)) => {
+            *lhs as f64 == *rhs
+        }
+        (Value::String(lhs), Value::String(rhs)) => lhs == rhs,
+        (Value::Bool(lhs), Value::Bool(rhs)) => lhs == rhs,
+        (Value::List(lhs), Value::List(rhs)) => {
+            lhs.len() == rhs.len() && lhs.iter().zip(rhs.iter()).all(|(l, r)| equal(l, r))
+        }
+        (Value::UserData(lhs), Value::UserData(rhs)) => Rc::ptr_eq(lhs, rhs),
+        (Value::Nil, Value::Nil) => true,
+        _ => false,
     }
 }
 
-#[test]
-fn vm1() {
+#[cfg(test)]
+mod test {
     extern crate eb_codegen_fast as codegen;
-    extern crate eb_lexer as lexer;
     extern crate eb_parser as parser;
-    use codegen::expr::visit;
-    use lexer::{source::Source, tokenize};
-    use parser::{expr::parse_body, Context as ParserContext};
-
-    let source = Source::String(
-        r#"
-            func f(x): 
-                if x == 1:
-                    return 1 ;;
-                x * f(x - 1) ;;
-            f(10) ;;"#
-            .to_string(),
-    );
-    let mut ctx = ParserContext::new(tokenize(&source));
-    let node = parse_body(&mut ctx).expect("fail to parse");
-    let mut ctx_ = FunctionContext::default();
-    visit(&mut ctx_, &node).unwrap();
-    let mut vm = VM::default();
-    vm.run(&ctx_);
-    assert!(matches!(vm.stack.pop().unwrap(), Value::Int(3628800)));
+    use super::*;
+    use lexer::{
+        source::{Source, SourceMap},
+        tokenize,
+    };
+    use parser::{expr::parse_program, Context as ParserContext};
+
+    fn compile(src: &str) -> FunctionContext {
+        let source = Source::String(src.to_string());
+        let mut ctx = ParserContext::new(tokenize(&source));
+        let node = parse_program(&mut ctx).expect("fail to parse");
+        let mut func = FunctionContext::default();
+        codegen::expr::visit(&mut func, &node).unwrap();
+        func
+    }
+
+    /// Runs `src` on `vm`, which keeps the globals of earlier runs.
+    fn run_in(vm: &mut VM, src: &str) -> Result<Value, RuntimeError> {
+        let result = vm.run(&compile(src));
+        assert!(vm.stack.is_empty());
+        result
+    }
+
+    fn run(src: &str) -> Result<Value, RuntimeError> {
+        run_in(&mut VM::default(), src)
+    }
+
+    #[test]
+    fn vm1() {
+        let src = r#"
+                func f(x): 
+                    if x == 1:
+                        return 1 ;;
+                    x * f(x - 1) ;;
+                f(10) ;;"#;
+        assert!(matches!(run(src), Ok(Value::Int(3628800))));
+    }
+
+    #[test]
+    fn vm2() {
+        let mut vm = VM::default();
+        for (src, expected) in &[
+            ("func double(x): x * 2 ;; double(3) ;;", 6),
+            ("double(double(5)) ;;", 20),
+        ] {
+            assert!(matches!(run_in(&mut vm, src), Ok(Value::Int(i)) if i == *expected));
+        }
+    }
+
+    #[test]
+    fn vm3() {
+        let src = r#"
+                func greet(name):
+                    if name == "\u{65}b":
+                        return "hello, \"eb\"\n" ;;
+                    "who?" ;;
+                greet("eb") ;;"#;
+        assert!(matches!(run(src), Ok(Value::String(s)) if s == "hello, \"eb\"\n"));
+    }
+
+    #[test]
+    fn vm4() {
+        let eval = |src: &str| run(src).unwrap();
+
+        assert!(matches!(eval("1.5 * 2.0 ;;"), Value::Float(f) if f == 3.0));
+        assert!(matches!(eval(".5 - 2e1 ;;"), Value::Float(f) if f == -19.5));
+        assert!(matches!(eval("3 * 0.5 ;;"), Value::Float(f) if f == 1.5));
+        assert!(matches!(eval("2.5 - 1 ;;"), Value::Float(f) if f == 1.5));
+        assert!(matches!(eval("7 - 2 ;;"), Value::Int(5)));
+        assert!(matches!(eval("2 * 1.0 ;;"), Value::Float(f) if f == 2.0));
+        assert!(matches!(eval("1 == 1.0 ;;"), Value::Bool(true)));
+        assert!(matches!(eval("0.5 == 1 ;;"), Value::Bool(false)));
+        assert!(matches!(eval("1.5e2 == 150.0 ;;"), Value::Bool(true)));
+        assert_eq!(eval("1 * 1.0 ;;").to_string(), "1.0");
+    }
+
+    #[test]
+    fn vm5() {
+        let mut vm = VM::default();
+        let mut run = |src: &str| run_in(&mut vm, src);
+        run("func t(): 1 == 1 ;; func f(): 1 != 1 ;;").unwrap();
+
+        // int operands
+        assert!(matches!(run("7 + 2"), Ok(Value::Int(9))));
+        assert!(matches!(run("7 - 2"), Ok(Value::Int(5))));
+        assert!(matches!(run("7 * 2"), Ok(Value::Int(14))));
+        assert!(matches!(run("7 / 2"), Ok(Value::Int(3))));
+        assert!(matches!(run("7 == 2"), Ok(Value::Bool(false))));
+        assert!(matches!(run("7 != 2"), Ok(Value::Bool(true))));
+        assert!(matches!(run("7.0 / 2"), Ok(Value::Float(f)) if f == 3.5));
+        assert!(matches!(run(r#""e" + "b""#), Ok(Value::String(s)) if s == "eb"));
+
+        // bool operands
+        assert!(matches!(run("t() == t()"), Ok(Value::Bool(true))));
+        assert!(matches!(run("t() == f()"), Ok(Value::Bool(false))));
+        assert!(matches!(run("t() != f()"), Ok(Value::Bool(true))));
+        assert!(matches!(run("t() == 1"), Ok(Value::Bool(false))));
+        assert!(matches!(run("t() != 1"), Ok(Value::Bool(true))));
+        for op in &["+", "-", "*", "/"] {
+            let err = run(&format!("t() {} 1", op)).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("unsupported operand types for `{}`: `bool` and `int`", op)
+            );
+            let err = run(&format!("1 {} f()", op)).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("unsupported operand types for `{}`: `int` and `bool`", op)
+            );
+        }
+        assert_eq!(
+            run("t() + f()").unwrap_err().to_string(),
+            "unsupported operand types for `+`: `bool` and `bool`"
+        );
+
+        // division by zero
+        assert_eq!(run("1 / 0").unwrap_err().to_string(), "division by zero");
+        assert_eq!(
+            run("1.5 / 0.0").unwrap_err().to_string(),
+            "division by zero"
+        );
+        assert_eq!(
+            run("func g(x): x / 0 ;; g(1)").unwrap_err().to_string(),
+            "division by zero"
+        );
+        assert!(matches!(run("6 / 3"), Ok(Value::Int(2))));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn vm6() {
+        let mut vm = VM::default();
+        let mut run = |src: &str| run_in(&mut vm, src);
+
+        // relational
+        assert!(matches!(run("1 < 2"), Ok(Value::Bool(true))));
+        assert!(matches!(run("2 <= 2"), Ok(Value::Bool(true))));
+        assert!(matches!(run("1 > 2"), Ok(Value::Bool(false))));
+        assert!(matches!(run("1.5 >= 2"), Ok(Value::Bool(false))));
+        assert!(matches!(run(r#""a" < "b""#), Ok(Value::Bool(true))));
+        assert!(matches!(run("1 + 1 < 3 == true"), Ok(Value::Bool(true))));
+        assert_eq!(
+            run("true < false").unwrap_err().to_string(),
+            "unsupported operand types for `<`: `bool` and `bool`"
+        );
+        assert_eq!(
+            run("1 >= true").unwrap_err().to_string(),
+            "unsupported operand types for `>=`: `int` and `bool`"
+        );
+
+        // unary
+        assert!(matches!(run("!true"), Ok(Value::Bool(false))));
+        assert!(matches!(run("!!1"), Ok(Value::Bool(true))));
+        assert!(matches!(run("-7 / 2"), Ok(Value::Int(-3))));
+        assert!(matches!(run("--2.5"), Ok(Value::Float(f)) if f == 2.5));
+        assert!(matches!(run("2 - -1"), Ok(Value::Int(3))));
+        assert_eq!(
+            run("-true").unwrap_err().to_string(),
+            "unsupported operand type for `-`: `bool`"
+        );
+
+        // logical
+        assert!(matches!(run("true && false"), Ok(Value::Bool(false))));
+        assert!(matches!(run("false || true"), Ok(Value::Bool(true))));
+        assert!(matches!(run("1 < 2 && 2 < 3"), Ok(Value::Bool(true))));
+        assert!(matches!(
+            run("false || 1 > 2 && true"),
+            Ok(Value::Bool(false))
+        ));
+        assert!(matches!(run("true && 5"), Ok(Value::Int(5))));
+        assert!(matches!(run("false && 1 / 0"), Ok(Value::Bool(false))));
+        assert!(matches!(run("true || 1 / 0"), Ok(Value::Bool(true))));
+        assert_eq!(
+            run("true && 1 / 0").unwrap_err().to_string(),
+            "division by zero"
+        );
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn vm7() {
+        assert!(matches!(run("(1 + 2) * 3"), Ok(Value::Int(9))));
+        assert!(matches!(run("1 + 2 * 3"), Ok(Value::Int(7))));
+        assert!(matches!(run("(((7)))"), Ok(Value::Int(7))));
+        assert!(matches!(run("10 - (4 - 3)"), Ok(Value::Int(9))));
+        assert!(matches!(run("10 - 4 - 3"), Ok(Value::Int(3))));
+        assert!(matches!(run("2 ** 3 ** 2"), Ok(Value::Int(512))));
+        assert!(matches!(run("(2 ** 3) ** 2"), Ok(Value::Int(64))));
+        assert!(matches!(run("-2 ** 2"), Ok(Value::Int(-4))));
+        assert!(matches!(run("(-2) ** 2"), Ok(Value::Int(4))));
+        assert!(matches!(run("2 ** -1"), Ok(Value::Float(f)) if f == 0.5));
+        assert!(matches!(run("4.0 ** 0.5"), Ok(Value::Float(f)) if f == 2.0));
+        for src in ["0 ** -1", "0.0 ** -1", "0 ** -0.5"] {
+            assert_eq!(run(src).unwrap_err().kind(), ErrorKind::DivisionByZero);
+        }
+        assert!(matches!(run("0 ** 0"), Ok(Value::Int(1))));
+        assert!(matches!(run("2 * 3 ** 2"), Ok(Value::Int(18))));
+        assert!(matches!(run("!(1 < 2 && 2 < 1)"), Ok(Value::Bool(true))));
+        assert!(matches!(
+            run("func sq(x): x ** 2 ;; (sq(3) + 1) * 2"),
+            Ok(Value::Int(20))
+        ));
+        assert_eq!(
+            run("true ** 2").unwrap_err().to_string(),
+            "unsupported operand types for `**`: `bool` and `int`"
+        );
+    }
+
+    #[test]
+    fn vm8() {
+        let twice = "func twice(): func double(x): x * 2 ;; double ;; ";
+        assert!(matches!(
+            run(&format!("{}twice()(21)", twice)),
+            Ok(Value::Int(42))
+        ));
+        assert!(matches!(
+            run(&format!("{}twice()(twice()(3)) + 1", twice)),
+            Ok(Value::Int(13))
+        ));
+        assert!(matches!(run(r#""héllo"[1]"#), Ok(Value::String(s)) if s == "é"));
+        assert!(
+            matches!(run(r#"func s(): "abc" ;; s()[1 + 1]"#), Ok(Value::String(s)) if s == "c")
+        );
+        assert_eq!(
+            run(r#""abc"[3]"#).unwrap_err().to_string(),
+            "index 3 is out of range for length 3"
+        );
+        assert_eq!(
+            run(r#""abc"[-1]"#).unwrap_err().to_string(),
+            "index -1 is out of range for length 3"
+        );
+        assert_eq!(
+            run("1[0]").unwrap_err().to_string(),
+            "unsupported operand types for `[]`: `int` and `int`"
+        );
+        assert_eq!(
+            run("1(2)").unwrap_err().to_string(),
+            "`int` value is not callable"
+        );
+        assert_eq!(
+            run(r#""abc".len"#).unwrap_err().to_string(),
+            "`string` value has no field `len`"
+        );
+    }
+
+    #[test]
+    fn vm9() {
+        let mut vm = VM::default();
+        let mut eval = |src: &str| run_in(&mut vm, src).unwrap();
+
+        eval(
+            r#"
+            func sign(x):
+                if x < 0: -1 ;;
+                else if x == 0: 0 ;;
+                else: 1 ;;
+            ;;
+            func abs(x): if x < 0: -x ;; else: x ;; ;;
+            func when(c): if c: "yes" ;; ;;
+        "#,
+        );
+        assert!(matches!(eval("sign(-5)"), Value::Int(-1)));
+        assert!(matches!(eval("sign(0)"), Value::Int(0)));
+        assert!(matches!(eval("sign(7)"), Value::Int(1)));
+        assert!(matches!(eval("abs(-3) + abs(4)"), Value::Int(7)));
+        assert!(matches!(eval("when(true)"), Value::String(s) if s == "yes"));
+        assert!(matches!(eval("when(false)"), Value::Nil));
+        assert!(matches!(eval("if false: 1 ;;"), Value::Nil));
+        assert!(matches!(
+            eval("abs(if true: -2 ;; else: 3 ;;)"),
+            Value::Int(2)
+        ));
+        assert!(matches!(
+            eval("if 1 > 2: 1 ;; else: if true: 2 ;; else: 3 ;; ;;"),
+            Value::Int(2)
+        ));
+        assert!(matches!(
+            eval("func f(): 1 ; 2 ; 3 ;; f() * 2"),
+            Value::Int(6)
+        ));
+        assert!(matches!(eval("func g(): ;; g()"), Value::Nil));
+        assert!(matches!(
+            eval("func h(x): if x: return 1 ;; 2 ;; h(true) + h(false)"),
+            Value::Int(3)
+        ));
+    }
+
+    #[test]
+    fn vm10() {
+        let mut vm = VM::default();
+        let mut run = |src: &str| run_in(&mut vm, src);
+
+        assert!(matches!(run("let x = 1 ; x"), Ok(Value::Int(1))));
+        assert!(matches!(run("x = x + 1"), Ok(Value::Int(2))));
+        assert!(matches!(run("x += 10 ; x"), Ok(Value::Int(12))));
+        assert!(matches!(
+            run("x -= 2 ; x *= 3 ; x /= 6 ; x"),
+            Ok(Value::Int(5))
+        ));
+        assert!(matches!(
+            run("let y = 0 ; x = y = 7 ; x + y"),
+            Ok(Value::Int(14))
+        ));
+        assert!(matches!(run(r#"let s = "e" ; s += "b""#), Ok(Value::String(s)) if s == "eb"));
+
+        // `let` inside a function binds a local that does not leak out.
+        assert!(matches!(
+            run("func f(a): let b = a * 2 ; b += 1 ; b ;; f(4)"),
+            Ok(Value::Int(9))
+        ));
+        assert_eq!(run("b").unwrap_err().to_string(), "undefined variable `b`");
+        // Assignment updates the variable where it was defined.
+        assert!(matches!(
+            run("let total = 0 ; func add(n): total += n ;; add(3) ; add(4) ; total"),
+            Ok(Value::Int(7))
+        ));
+        assert_eq!(
+            run("nope = 1").unwrap_err().to_string(),
+            "undefined variable `nope`"
+        );
+        assert_eq!(run("x /= 0").unwrap_err().to_string(), "division by zero");
+        assert!(matches!(run("x"), Ok(Value::Int(7))));
+    }
+
+    #[test]
+    fn vm11() {
+        let mut vm = VM::default();
+        let mut run = |src: &str| run_in(&mut vm, src);
+
+        assert!(matches!(
+            run("let i = 0 ; let sum = 0 ; while i < 100000: i += 1 ; sum += i ;; sum"),
+            Ok(Value::Int(5000050000))
+        ));
+        assert!(matches!(run("while false: 1 ;;"), Ok(Value::Nil)));
+        assert!(matches!(
+            run("let n = 0 ; while true: n += 1 ; if n == 5: break n * 10 ;; ;;"),
+            Ok(Value::Int(50))
+        ));
+        // Odd numbers only.
+        assert!(matches!(
+            run("let i = 0 ; let sum = 0 ; while i < 10: i += 1 ; if i / 2 * 2 == i: continue ;; sum += i ;; sum"),
+            Ok(Value::Int(25))
+        ));
+        // `break` pops the operands waiting for it.
+        assert!(matches!(
+            run("let i = 0 ; 1 + while true: i += 1 ; f(2, 3 + break i) ;;"),
+            Ok(Value::Int(2))
+        ));
+    }
+
+    #[test]
+    fn vm12() {
+        let mut vm = VM::default();
+        let mut run = |src: &str| run_in(&mut vm, src);
+
+        assert!(matches!(
+            run("let sum = 0 ; for x in [1, 2, 3, 4]: sum += x ;; sum"),
+            Ok(Value::Int(10))
+        ));
+        assert!(matches!(
+            run(r#"let s = "" ; for c in "abc": s = c + s ;; s"#),
+            Ok(Value::String(s)) if s == "cba"
+        ));
+        assert!(matches!(run("for x in []: x ;;"), Ok(Value::Nil)));
+        assert!(matches!(run("[1, [2, 3]][1][0]"), Ok(Value::Int(2))));
+        assert_eq!(
+            run("[1, 2][2]").unwrap_err().to_string(),
+            "index 2 is out of range for length 2"
+        );
+        assert_eq!(
+            run("for x in 1: x ;;").unwrap_err().to_string(),
+            "`int` value is not iterable"
+        );
+
+        // `break` and `continue` apply to the innermost loop.
+        assert!(matches!(
+            run(
+                "let found = false
+                for x in [1, 2, 3]:
+                    found = for y in [4, 5, 6]:
+                        if y == 5: continue ;;
+                        if x * y == 12: break [x, y] ;;
+                    ;;
+                    if found: break found ;;
+                ;;"
+            ),
+            Ok(Value::List(pair)) if pair.len() == 2 && matches!(pair[0], Value::Int(2))
+        ));
+
+        // Deep iteration does not grow the frames, unlike recursion.
+        assert!(matches!(
+            run("func f(n): let i = 0 ; while i < n: i += 1 ;; i ;; f(1000000)"),
+            Ok(Value::Int(1000000))
+        ));
+    }
+
+    #[test]
+    fn vm13() {
+        let mut vm = VM::default();
+        let mut run = |src: &str| run_in(&mut vm, src);
+
+        // Each call has its own slots.
+        assert!(matches!(
+            run(
+                "func fib(n): let a = n ; if a < 2: return a ;; fib(a - 1) + fib(a - 2) ;; fib(15)"
+            ),
+            Ok(Value::Int(610))
+        ));
+        // Nested functions are locals, callable before their definition.
+        assert!(matches!(
+            run("func f(x): let y = g(x) ; func g(z): z * 3 ;; y + 1 ;; f(2)"),
+            Ok(Value::Int(7))
+        ));
+        // Names not bound in a function are globals.
+        assert!(matches!(
+            run("let count = 0 ; func bump(by): count += by ;; bump(2) ; bump(3) ; count"),
+            Ok(Value::Int(5))
+        ));
+        // Locals of the caller are not visible to the callee.
+        assert_eq!(
+            run("func h(): secret ;; func k(): let secret = 1 ; h() ;; k()")
+                .unwrap_err()
+                .to_string(),
+            "undefined variable `secret`"
+        );
+        assert!(!vm.globals.contains_key("secret"));
+    }
+
+    #[test]
+    fn vm14() {
+        let ctx_ = compile(r#"func f(): func g(): 1 ;; g ;; [f, f(), f()]"#);
+        let mut vm = VM::default();
+        let val = vm.run(&ctx_).unwrap();
+
+        // Reading a function or making a closure of it shares it instead of copying it.
+        let funcs: Vec<_> = match val {
+            Value::List(elems) => elems
+                .iter()
+                .map(|elem| match elem {
+                    Value::Func(func) => func.clone(),
+                    Value::Closure(closure) => closure.func.clone(),
+                    _ => panic!(),
+                })
+                .collect(),
+            _ => panic!(),
+        };
+        assert!(Rc::ptr_eq(&funcs[0], &ctx_.children[0]));
+        assert!(Rc::ptr_eq(&funcs[1], &funcs[2]));
+        assert!(Rc::ptr_eq(&funcs[1], &funcs[0].children[0]));
+    }
+
+    #[test]
+    fn vm15() {
+        let mut vm = VM::default();
+        let mut run = |src: &str| run_in(&mut vm, src);
+
+        // Counters keep their own state across calls.
+        run("func counter():
+                let n = 0
+                func next(): n += 1 ;;
+                next ;;
+            let a = counter() ; let b = counter()")
+        .unwrap();
+        assert!(matches!(
+            run("a() ; a() ; b() ; [a(), b()]"),
+            Ok(Value::List(ns)) if matches!(ns[..], [Value::Int(3), Value::Int(2)])
+        ));
+
+        // Adders capture a parameter.
+        assert!(matches!(
+            run("func adder(x): func add(y): x + y ;; add ;; let add2 = adder(2) ; add2(3) + adder(10)(add2(0))"),
+            Ok(Value::Int(17))
+        ));
+
+        // Variables are captured through several levels, and assignments are seen by every closure
+        // sharing them.
+        assert!(matches!(
+            run("func outer(x):
+                    func middle():
+                        func inner(): x *= 2 ;;
+                        inner ;;
+                    let f = middle() ; f() ; f() ; x ;;
+                outer(3)"),
+            Ok(Value::Int(12))
+        ));
+
+        // A nested function can call itself, and the closure outlives the frame it was created in.
+        assert!(matches!(
+            run("func make(): func fact(n): if n <= 1: 1 ;; else: n * fact(n - 1) ;; ;; fact ;; make()(10)"),
+            Ok(Value::Int(3628800))
+        ));
+    }
+
+    #[test]
+    fn vm16() {
+        let mut vm = VM::default();
+        let mut run = |src: &str| run_in(&mut vm, src);
+
+        let err = run("let x = 1 ; x / 0").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DivisionByZero);
+        assert_eq!(err.inst(), 5);
+
+        // The index is into the code of the failing function.
+        let err = run("func f(a): a - nope ;; 1 ; f(1)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UndefinedName);
+        assert_eq!(err.message(), "undefined variable `nope`");
+        assert_eq!(err.inst(), 1);
+
+        let err = run("f(1, 2)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ArityMismatch);
+        assert_eq!(err.message(), "`f` takes 1 argument(s) but 2 were given");
+        assert_eq!(run("f()").unwrap_err().kind(), ErrorKind::ArityMismatch);
+        assert_eq!(run("[] + 1").unwrap_err().kind(), ErrorKind::TypeError);
+        assert_eq!(run("nil()").unwrap_err().kind(), ErrorKind::UndefinedName);
+        assert_eq!(run("[][0]").unwrap_err().kind(), ErrorKind::IndexOutOfRange);
+
+        // The VM is still usable after errors, deep in calls or not.
+        assert!(matches!(
+            run("func g(n): if n == 0: 1 / 0 ;; g(n - 1) ;; 1"),
+            Ok(Value::Int(1))
+        ));
+        assert!(run("g(100)").is_err());
+        assert!(matches!(
+            run("func h(a): a * 2 ;; f = h ; f(21)"),
+            Ok(Value::Int(42))
+        ));
+    }
+
+    #[test]
+    fn vm17() {
+        let src = r#"
+func fact(n):
+    if n == 0:
+        return 1 / n ;;
+    n * fact(n - 1) ;;
+fact(2)"#;
+        let err = run(src).unwrap_err();
+        // `compile` tokenizes with the first file id of a map.
+        let mut map = SourceMap::default();
+        map.add(Source::String(src.to_string()));
+        let trace: Vec<_> = err
+            .trace()
+            .iter()
+            .map(|frame| format!("{} {}", frame.func, map.location(frame.span.unwrap())))
+            .collect();
+        assert_eq!(
+            trace,
+            [
+                "fact <string>:4:16",
+                "fact <string>:5:9",
+                "fact <string>:5:9",
+                " <string>:6:1"
+            ]
+        );
+
+        // Code without a line table has no spans.
+        let mut ctx_ = FunctionContext::default();
+        ctx_.push(Inst::Get("nope".to_owned()));
+        let err = VM::default().run(&ctx_).unwrap_err();
+        assert_eq!(
+            err.trace(),
+            [TraceFrame {
+                func: "".to_owned(),
+                span: None
+            }]
+        );
+    }
+
+    #[test]
+    fn vm18() {
+        let mut vm = VM::default();
+        let out = Rc::new(RefCell::new(vec![]));
+        let out_ = out.clone();
+        vm.register_fn(
+            "print",
+            move |args: &[Value]| -> Result<Value, RuntimeError> {
+                let line: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                out_.borrow_mut().push(line.join(" "));
+                Ok(Value::Nil)
+            },
+        );
+        vm.register_fn("add", |a: i64, b: i64| a + b);
+        vm.register_fn("sqrt", f64::sqrt);
+        vm.register_fn("fail", |args: &[Value]| match args {
+            [] => Err("failed"),
+            _ => Ok(Value::Nil),
+        });
+        vm.register_fn("check", |args: &[Value]| match args {
+            [Value::Bool(true)] => Ok(Value::Nil),
+            _ => Err(RuntimeError::new(ErrorKind::IndexOutOfRange, "checked")),
+        });
+
+        let mut run = |src: &str| run_in(&mut vm, src);
+
+        assert!(matches!(
+            run("func twice(f, x): f(f(x)) ;; print(\"a\", 1, [2]) ; twice(print, 3) ; add(1, 2)"),
+            Ok(Value::Int(3))
+        ));
+        assert_eq!(*out.borrow(), ["a 1 [2]", "3", "nil"]);
+        assert!(matches!(run("sqrt(4)"), Ok(Value::Float(f)) if f == 2.0));
+        assert_eq!(run("add").unwrap().to_string(), "<native func add>");
+
+        let err = run("add(1)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ArityMismatch);
+        assert_eq!(err.message(), "`add` takes 2 argument(s) but 1 were given");
+        let err = run("add(1, \"x\")").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Conversion);
+        assert_eq!(err.message(), "expected `int` value, found `string`");
+        let err = run("1 ; fail()").unwrap_err();
+        assert_eq!((err.kind(), err.message()), (ErrorKind::Native, "failed"));
+        assert_eq!(err.inst(), 3);
+        assert!(matches!(run("fail(1)"), Ok(Value::Nil)));
+        let err = run("check(1 == 2)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::IndexOutOfRange);
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn vm19() {
+        let src = r#"
+            let events = [] ;
+            let limit = 2 ;
+            func on_event(kind, payload):
+                if kind > limit: return kind / 0 ;;
+                events = [kind, payload, events] ;
+                [kind, payload] ;;
+            func pair(a, b): [a, b] ;;
+            func apply(f, x): f(x) ;;"#;
+        let mut vm = VM::default();
+        run_in(&mut vm, src).unwrap();
+
+        let (kind, payload): (i64, String) = vm.call("on_event", (1, "x")).unwrap();
+        assert_eq!((kind, payload.as_str()), (1, "x"));
+        let val: Value = vm.call("on_event", (2, vec![Some(1.5), None])).unwrap();
+        assert_eq!(val.to_string(), "[2, [1.5, nil]]");
+        assert_eq!(
+            vm.get_global::<Value>("events").unwrap().to_string(),
+            "[2, [1.5, nil], [1, x, []]]"
+        );
+        assert_eq!(vm.get_global::<u8>("limit").unwrap(), 2);
+        let xs: Vec<Option<Vec<i64>>> = vm.call("pair", (None::<i64>, vec![1, 2])).unwrap();
+        assert_eq!(xs, [None, Some(vec![1, 2])]);
+
+        // Functions taken from globals can be passed back.
+        let f: Value = vm.get_global("on_event").unwrap();
+        let g: Value = vm.get_global("apply").unwrap();
+        assert!(vm.call_value::<_, (i64, ())>(f.clone(), (1, ())).is_ok());
+        assert!(vm.call_value::<_, Value>(g, (f, 1)).is_err());
+
+        let err = vm.call::<_, Value>("on_event", (3, "y")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DivisionByZero);
+        assert_eq!(err.trace().len(), 1);
+        assert_eq!(err.trace()[0].func, "on_event");
+        let err = vm.call::<_, Value>("on_event", (1,)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ArityMismatch);
+        let err = vm.call::<_, Value>("nope", ()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UndefinedName);
+        let err = vm.call::<_, i64>("on_event", (1, "x")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Conversion);
+        assert_eq!(err.message(), "expected `int` value, found `list`");
+        assert_eq!(
+            err.conversion(),
+            Some(&ConversionError::TypeMismatch {
+                expected: "int",
+                found: "list"
+            })
+        );
+        assert_eq!(
+            vm.get_global::<(i64,)>("events").unwrap_err().conversion(),
+            Some(&ConversionError::LengthMismatch {
+                expected: 1,
+                found: 3
+            })
+        );
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn vm20() {
+        struct Entity {
+            x: i64,
+            y: i64,
+            name: String,
+        }
+
+        let dropped = Rc::new(RefCell::new(vec![]));
+        let dropped_ = dropped.clone();
+        let mut vm = VM::default();
+        vm.register_type(
+            TypeBuilder::<Entity>::new("Entity")
+                .with_field("x", |ent| ent.x)
+                .with_field("y", |ent| ent.y)
+                .with_method("move", |ent: &mut Entity, dx: i64, dy: i64| {
+                    ent.x += dx;
+                    ent.y += dy;
+                })
+                .with_method("rename", |ent: &mut Entity, args: &[Value]| match args {
+                    [Value::String(name)] => {
+                        ent.name = name.clone();
+                        Ok(Value::Nil)
+                    }
+                    _ => Err("expected a name"),
+                })
+                .with_drop(move |ent| dropped_.borrow_mut().push(ent.name.clone())),
+        );
+        vm.register_fn("spawn", |x: i64| x);
+        let ent = vm.userdata(Entity {
+            x: 1,
+            y: 2,
+            name: "a".to_owned(),
+        });
+        vm.globals.insert("ent".to_owned(), ent);
+
+        let mut run = |src: &str| run_in(&mut vm, src);
+
+        assert!(matches!(
+            run("ent.move(1, 2) ; let m = ent.move ; m(10, 0) ; ent.x * 100 + ent.y"),
+            Ok(Value::Int(1204))
+        ));
+        assert!(matches!(run("ent == ent"), Ok(Value::Bool(true))));
+        assert_eq!(
+            run("[ent, ent.move]").unwrap().to_string(),
+            "[<Entity>, <native func Entity.move>]"
+        );
+        let err = run("ent.z").unwrap_err();
+        assert_eq!(err.message(), "`Entity` value has no field `z`");
+        let err = run("ent.move(1)").unwrap_err();
+        assert_eq!(
+            err.message(),
+            "`Entity.move` takes 2 argument(s) but 1 were given"
+        );
+        let err = run("ent.rename(1)").unwrap_err();
+        assert_eq!(
+            (err.kind(), err.message()),
+            (ErrorKind::Native, "expected a name")
+        );
+        let err = run("spawn(ent)").unwrap_err();
+        assert_eq!(err.message(), "expected `int` value, found `Entity`");
+        run("ent.rename(\"b\")").unwrap();
+
+        // The host downcasts the data, which is checked against its type.
+        let ent: Rc<UserData> = vm.get_global("ent").unwrap();
+        assert!(ent.is::<Entity>() && !ent.is::<i64>());
+        assert!(ent.borrow::<i64>().is_none());
+        assert_eq!(ent.borrow::<Entity>().unwrap().x, 12);
+        {
+            let _guard = ent.borrow_mut::<Entity>().unwrap();
+            let mut ctx = FunctionContext::default();
+            ctx.push(Inst::Get("ent".to_owned()));
+            ctx.push(Inst::GetField("x".to_owned()));
+            let err = vm.run(&ctx).unwrap_err();
+            assert_eq!(err.message(), "`Entity` value is already borrowed");
+        }
+
+        // The bound method `m` holds the receiver too.
+        assert!(dropped.borrow().is_empty());
+        drop(ent);
+        vm.globals.remove("ent");
+        assert!(dropped.borrow().is_empty());
+        vm.globals.remove("m");
+        assert_eq!(*dropped.borrow(), ["b"]);
+    }
+
+    #[test]
+    fn vm21() {
+        let min = "(-9223372036854775807 - 1)";
+        for src in &[
+            "9223372036854775807 + 1".to_owned(),
+            "-9223372036854775807 - 2".to_owned(),
+            "3037000500 * 3037000500".to_owned(),
+            "2 ** 63".to_owned(),
+            "2 ** 5000000000".to_owned(),
+            format!("{} / -1", min),
+            format!("-{}", min),
+            "let x = 9223372036854775807 ; x += 1".to_owned(),
+        ] {
+            let err = run(src).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Overflow, "{}", src);
+        }
+        assert_eq!(
+            run("9223372036854775807 + 1").unwrap_err().message(),
+            "integer overflow in `+`"
+        );
+        assert!(matches!(
+            run(&format!("{} + 0", min)),
+            Ok(Value::Int(i64::MIN))
+        ));
+        assert!(matches!(
+            run("2 ** 62"),
+            Ok(Value::Int(4611686018427387904))
+        ));
+        assert!(matches!(run("-1 ** 5000000001"), Ok(Value::Int(-1))));
+        assert!(matches!(run("1 ** 5000000000"), Ok(Value::Int(1))));
+        assert!(matches!(
+            run("9223372036854775807 + 1.0"),
+            Ok(Value::Float(_))
+        ));
+    }
+
+    #[test]
+    fn vm22() {
+        use vm_ctx::inst::Code;
+
+        let mut vm = VM::default();
+        let code = |insts: Vec<Inst>| FunctionContext {
+            code: Code(insts),
+            ..FunctionContext::default()
+        };
+        for (insts, message) in vec![
+            (vec![Inst::Add, Inst::Ret], "stack underflow"),
+            (vec![Inst::PushInt(1), Inst::Call(1)], "stack underflow"),
+            (vec![Inst::MakeList(2)], "stack underflow"),
+            (vec![Inst::SetLocal(0)], "stack underflow"),
+            (vec![Inst::GetLocal(0)], "local slot 0 does not exist"),
+            (vec![Inst::GetUpvalue(1)], "upvalue 1 does not exist"),
+            (vec![Inst::MakeClosure(0)], "function 0 does not exist"),
+            (vec![Inst::PushNil, Inst::IterNext(1)], "stack underflow"),
+            (
+                vec![Inst::PushNil, Inst::PushNil, Inst::IterNext(1)],
+                "no iteration in progress",
+            ),
+        ] {
+            let err = vm.run(&code(insts)).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidCode);
+            assert_eq!(err.message(), message);
+            assert!(vm.stack.is_empty());
+        }
+        // A function cannot pop the locals of its frame.
+        let mut ctx = code(vec![Inst::Pop]);
+        ctx.locals.push("x".to_owned());
+        let err = vm.run(&ctx).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidCode);
+        assert!(vm.stack.is_empty());
+        // The VM is still usable.
+        assert!(matches!(run_in(&mut vm, "1 + 2"), Ok(Value::Int(3))));
+    }
+
+    #[test]
+    fn vm23() {
+        let err = run("func f(n): f(n + 1) ;; f(0)").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StackOverflow);
+        assert_eq!(err.message(), "maximum call depth of 1000 exceeded");
+        assert_eq!(err.trace().len(), MAX_FRAMES);
+        assert!(err.trace()[..MAX_FRAMES - 1]
+            .iter()
+            .all(|frame| frame.func == "f"));
+
+        let src = format!(
+            "func f(n): if n == 0: return 0 ;; f(n - 1) ;; f({})",
+            MAX_FRAMES - 2
+        );
+        assert!(matches!(run(&src), Ok(Value::Int(0))));
+    }
+
+    #[test]
+    fn vm24() {
+        assert!(matches!(
+            run(r#"let s = "" ; for c in "añ€😀": s = c + s ;; s"#),
+            Ok(Value::String(s)) if s == "😀€ña"
+        ));
+        assert!(matches!(
+            run(r#"let n = 0 ; for c in "añ€😀": n = n + 1 ;; n"#),
+            Ok(Value::Int(4))
+        ));
+        assert!(matches!(run(r#""añ€"[2]"#), Ok(Value::String(s)) if s == "€"));
+        assert_eq!(
+            run(r#""añ€"[3]"#).unwrap_err().message(),
+            "index 3 is out of range for length 3"
+        );
+
+        // An offset inside a character is not a valid state.
+        let ctx = FunctionContext {
+            code: vm_ctx::inst::Code(vec![
+                Inst::PushStr("ñ".to_owned()),
+                Inst::PushInt(1),
+                Inst::IterNext(1),
+            ]),
+            ..FunctionContext::default()
+        };
+        let err = VM::default().run(&ctx).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidCode);
+    }
+
+    #[test]
+    fn vm25() {
+        let closure = |src: &str| match run(src) {
+            Ok(Value::Closure(closure)) => Rc::downgrade(&closure),
+            _ => panic!(),
+        };
+
+        // A recursive closure is freed along with the variables it captured.
+        let fact = closure(
+            "func make(x): func fact(n): if n <= 1: x ;; else: n * fact(n - 1) ;; ;; fact ;;
+            make(1)",
+        );
+        assert!(fact.upgrade().is_none());
+        // So is one whose nested functions refer to it.
+        let outer = closure(
+            "func make(): func outer(n): func inner(): outer(n - 1) ;; if n == 0: 0 ;; else: inner() ;; ;; outer ;;
+            make()",
+        );
+        assert!(outer.upgrade().is_none());
+
+        // Mutually recursive closures hold each other, and are never freed.
+        let even = closure(
+            "func make():
+                func even(n): if n == 0: true ;; else: odd(n - 1) ;; ;;
+                func odd(n): if n == 0: false ;; else: even(n - 1) ;; ;;
+                even ;;
+            make()",
+        );
+        assert!(even.upgrade().is_some());
+    }
+
+    #[test]
+    fn vm26() {
+        struct Handle;
+
+        let dropped = Rc::new(RefCell::new(0));
+        let new_vm = || {
+            let dropped = dropped.clone();
+            let mut vm = VM::default();
+            vm.register_type(
+                TypeBuilder::<Handle>::new("Handle").with_drop(move |_| *dropped.borrow_mut() += 1),
+            );
+            let handle = vm.userdata(Handle);
+            vm.globals.insert("handle".to_owned(), handle);
+            vm
+        };
+
+        // The hook runs for a value captured by a recursive nested function.
+        let mut vm = new_vm();
+        run_in(
+            &mut vm,
+            "func make(h): func count(n): if n == 0: h ;; else: count(n - 1) ;; ;; count ;;
+            let count = make(handle) ; count(3)",
+        )
+        .unwrap();
+        vm.globals.remove("handle");
+        assert_eq!(*dropped.borrow(), 0);
+        drop(vm);
+        assert_eq!(*dropped.borrow(), 1);
+
+        // It never runs for a value captured by nested functions that call each other.
+        let mut vm = new_vm();
+        run_in(
+            &mut vm,
+            "func make(h):
+                func ping(n): if n == 0: h ;; else: pong(n - 1) ;; ;;
+                func pong(n): ping(n) ;;
+                ping ;;
+            make(handle)(3)",
+        )
+        .unwrap();
+        drop(vm);
+        assert_eq!(*dropped.borrow(), 1);
+    }
 }
@@ -0,0 +1,70 @@
+//! Host functions that scripts can call. See `VM::register_fn`.
+
+use super::{
+    convert::{FromValue, IntoValue},
+    RuntimeError,
+};
+use std::error::Error;
+use vm_ctx::value::Value;
+
+/// The body of a native function, which takes the arguments of a call.
+pub type NativeFn = Box<dyn Fn(&[Value]) -> Result<Value, Box<dyn Error>>>;
+
+/// A Rust function that can be registered as a native function. `Args` tells the implementations
+/// apart: `Variadic` for closures taking `&[Value]`, and the tuple of the parameter types for
+/// typed functions.
+pub trait IntoNative<Args> {
+    /// Returns the number of arguments the function takes, or `None` if it takes any number.
+    fn arity() -> Option<usize>;
+
+    fn into_native(self) -> NativeFn;
+}
+
+/// Marks functions that take their arguments as `&[Value]`.
+pub enum Variadic {}
+
+impl<F, E> IntoNative<Variadic> for F
+where
+    F: Fn(&[Value]) -> Result<Value, E> + 'static,
+    E: Into<Box<dyn Error>>,
+{
+    fn arity() -> Option<usize> {
+        None
+    }
+
+    fn into_native(self) -> NativeFn {
+        Box::new(move |args| self(args).map_err(Into::into))
+    }
+}
+
+/// Implements `IntoNative` for functions with the parameter types `$arg`. The arity is checked
+/// before the call, so there is an argument for each parameter.
+macro_rules! impl_into_native {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> IntoNative<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: IntoValue,
+            $($arg: FromValue,)*
+        {
+            fn arity() -> Option<usize> {
+                Some(<[&str]>::len(&[$(stringify!($arg)),*]))
+            }
+
+            #[allow(unused_variables, unused_mut)]
+            fn into_native(self) -> NativeFn {
+                Box::new(move |args| {
+                    let mut args = args.iter().cloned();
+                    let val = self($($arg::from_value(args.next().unwrap()).map_err(RuntimeError::from)?),*);
+                    Ok(val.into_value())
+                })
+            }
+        }
+    };
+}
+
+impl_into_native!();
+impl_into_native!(A);
+impl_into_native!(A, B);
+impl_into_native!(A, B, C);
+impl_into_native!(A, B, C, D);
@@ -0,0 +1,126 @@
+//! Host types whose values scripts can hold. See `VM::register_type`.
+
+use super::{
+    convert::{FromValue, IntoValue},
+    RuntimeError,
+};
+use std::{any::Any, error::Error, marker::PhantomData, rc::Rc};
+use vm_ctx::value::{Method, UserType, Value};
+
+/// Builds the `UserType` of the host type `T`.
+pub struct TypeBuilder<T> {
+    ty: UserType,
+    marker: PhantomData<T>,
+}
+
+/// A Rust function that can be a method of `T`. `Args` tells the implementations apart, as for
+/// `IntoNative`: `Variadic` for closures taking `&mut T` and `&[Value]`, and the tuple of the
+/// parameter types after the receiver for typed functions.
+pub trait IntoMethod<T, Args> {
+    /// Returns the number of arguments the method takes, or `None` if it takes any number.
+    fn arity() -> Option<usize>;
+
+    #[allow(clippy::type_complexity)]
+    fn into_method(self) -> Rc<dyn Fn(&mut T, &[Value]) -> Result<Value, Box<dyn Error>>>;
+}
+
+impl<T: Any> TypeBuilder<T> {
+    /// Starts a type shown as `name` to scripts.
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            ty: UserType::new(name),
+            marker: PhantomData,
+        }
+    }
+
+    /// Adds the field `name`, whose value is given by `get`.
+    pub fn with_field<V: IntoValue>(mut self, name: &str, get: impl Fn(&T) -> V + 'static) -> Self {
+        let get = move |data: &dyn Any| get(data.downcast_ref().unwrap()).into_value();
+        self.ty.fields.insert(name.to_owned(), Box::new(get));
+        self
+    }
+
+    /// Adds the method `name`, which scripts call as `value.name(args...)`.
+    pub fn with_method<F, Args>(mut self, name: &str, method: F) -> Self
+    where
+        F: IntoMethod<T, Args>,
+    {
+        let method_ = method.into_method();
+        let method = Method {
+            arity: F::arity(),
+            func: Rc::new(move |data: &mut dyn Any, args: &[Value]| {
+                method_(data.downcast_mut().unwrap(), args)
+            }),
+        };
+        self.ty.methods.insert(name.to_owned(), method);
+        self
+    }
+
+    /// Sets a hook called with the data when the last reference to a value is dropped. Closures of
+    /// nested functions that call each other are never freed, so the hook does not run for values
+    /// they captured.
+    pub fn with_drop(mut self, drop: impl Fn(&mut T) + 'static) -> Self {
+        self.ty.drop = Some(Box::new(move |data: &mut dyn Any| {
+            drop(data.downcast_mut().unwrap())
+        }));
+        self
+    }
+
+    pub fn build(self) -> UserType {
+        self.ty
+    }
+}
+
+/// Marks methods that take their arguments as `&[Value]`.
+pub enum Variadic {}
+
+impl<T, F, E> IntoMethod<T, Variadic> for F
+where
+    F: Fn(&mut T, &[Value]) -> Result<Value, E> + 'static,
+    E: Into<Box<dyn Error>>,
+{
+    fn arity() -> Option<usize> {
+        None
+    }
+
+    fn into_method(self) -> Rc<dyn Fn(&mut T, &[Value]) -> Result<Value, Box<dyn Error>>> {
+        Rc::new(move |data, args| self(data, args).map_err(Into::into))
+    }
+}
+
+/// Implements `IntoMethod` for functions with the parameter types `$arg` after the receiver. The
+/// arity is checked before the call, so there is an argument for each parameter.
+macro_rules! impl_into_method {
+    ($($arg:ident),*) => {
+        impl<T, F, R, $($arg),*> IntoMethod<T, ($($arg,)*)> for F
+        where
+            F: Fn(&mut T, $($arg),*) -> R + 'static,
+            R: IntoValue,
+            $($arg: FromValue,)*
+        {
+            fn arity() -> Option<usize> {
+                Some(<[&str]>::len(&[$(stringify!($arg)),*]))
+            }
+
+            #[allow(unused_variables, unused_mut)]
+            fn into_method(
+                self,
+            ) -> Rc<dyn Fn(&mut T, &[Value]) -> Result<Value, Box<dyn Error>>> {
+                Rc::new(move |data, args| {
+                    let mut args = args.iter().cloned();
+                    let val = self(
+                        data,
+                        $($arg::from_value(args.next().unwrap()).map_err(RuntimeError::from)?),*
+                    );
+                    Ok(val.into_value())
+                })
+            }
+        }
+    };
+}
+
+impl_into_method!();
+impl_into_method!(A);
+impl_into_method!(A, B);
+impl_into_method!(A, B, C);
+impl_into_method!(A, B, C, D);
@@ -1,13 +1,62 @@
 #[derive(Debug, Clone)]
 pub enum Inst {
     PushInt(i64),
+    PushFloat(f64),
+    PushBool(bool),
     PushStr(String),
+    PushNil,
+    Pop,
+    /// Pushes the value of a global variable.
     Get(String),
-    Call,
+    /// Binds a new global variable to the top of the stack, leaving it there.
+    Define(String),
+    /// Assigns the top of the stack to an existing global variable, leaving it there.
+    Set(String),
+    /// Pushes the value of a local variable, identified by its slot in the current frame.
+    GetLocal(u16),
+    /// Assigns the top of the stack to a local variable, leaving it there.
+    SetLocal(u16),
+    /// Pushes the value of a variable shared with closures, identified by its index in the
+    /// upvalues of the current frame.
+    GetUpvalue(u16),
+    /// Assigns the top of the stack to a variable shared with closures, leaving it there.
+    SetUpvalue(u16),
+    /// Pushes a closure of the given child of the current function, which captures upvalues of
+    /// the current frame.
+    MakeClosure(usize),
+    /// Pushes the function running in the current frame, as it was called.
+    GetCallee,
+    /// Calls the function on top of the stack with the given number of arguments below it.
+    Call(usize),
+    /// Pops the given number of values and pushes a list of them, the deepest one first.
+    MakeList(usize),
+    Index,
+    GetField(String),
+    Add,
     Sub,
     Mul,
+    Div,
+    Pow,
     Eq,
+    Neq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Not,
+    Neg,
+    Jmp(i32),
     Jne(i32),
+    /// Jumps by the offset if the top of the stack is falsy, leaving it there. Otherwise pops it.
+    JmpIfFalseOrPop(i32),
+    /// Jumps by the offset if the top of the stack is truthy, leaving it there. Otherwise pops it.
+    JmpIfTrueOrPop(i32),
+    /// Starts iterating over the list or string on top of the stack by pushing the position of
+    /// the next element after it: an index for a list, a byte offset for a string.
+    Iter,
+    /// Pushes the next element of the iteration below the top of the stack and advances it. Once
+    /// there are no elements left, pops the iteration state and jumps by the offset.
+    IterNext(i32),
     Ret,
 }
 
@@ -26,6 +75,10 @@ impl Code {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
 // pub struct Inst(u32);
@@ -1,12 +1,29 @@
+extern crate eb_lexer as lexer;
+
 pub mod inst;
 pub mod value;
 
+use lexer::location::Span;
+use std::rc::Rc;
+
 #[derive(Debug, Clone)]
 pub struct FunctionContext {
     pub name: String,
     pub param_names: Vec<String>,
+    /// Names of the slots for local variables in a frame of this function. The parameters come
+    /// first.
+    pub locals: Vec<String>,
+    /// Indices in the upvalues of the enclosing frame of those a closure of this function
+    /// captures. They are the first upvalues of the frames of the closure.
+    pub captures: Vec<u16>,
+    /// Number of upvalues that a frame of this function creates for its locals captured by nested
+    /// functions. They follow the captured ones.
+    pub captured_locals: usize,
     pub code: inst::Code,
-    pub children: Vec<Self>, // TODO: Vec<Rc<Self>>
+    /// Line table. Each entry gives the span of the source the instructions from its index up to
+    /// the next entry were compiled from.
+    pub spans: Vec<(usize, Span)>,
+    pub children: Vec<Rc<Self>>,
 }
 
 impl Default for FunctionContext {
@@ -14,7 +31,11 @@ impl Default for FunctionContext {
         Self {
             name: "".to_owned(),
             param_names: vec![],
+            locals: vec![],
+            captures: vec![],
+            captured_locals: 0,
             code: inst::Code(vec![]),
+            spans: vec![],
             children: vec![],
         }
     }
@@ -25,7 +46,21 @@ impl FunctionContext {
         self.code.0.push(inst)
     }
 
+    /// Pushes `inst`, compiled from the source at `span`.
+    pub fn push_at(&mut self, inst: inst::Inst, span: Span) {
+        if self.spans.last().map(|(_, s)| *s) != Some(span) {
+            self.spans.push((self.code.0.len(), span));
+        }
+        self.push(inst)
+    }
+
+    /// Returns the span of the source the instruction at `pc` was compiled from.
+    pub fn span(&self, pc: usize) -> Option<Span> {
+        let idx = self.spans.partition_point(|(start, _)| *start <= pc);
+        idx.checked_sub(1).map(|idx| self.spans[idx].1)
+    }
+
     pub fn add_child(&mut self, ctx: Self) {
-        self.children.push(ctx)
+        self.children.push(Rc::new(ctx))
     }
 }
@@ -1,10 +1,181 @@
 use super::FunctionContext;
+use std::{
+    any::Any,
+    cell::{Ref, RefCell, RefMut},
+    collections::HashMap,
+    error::Error,
+    fmt,
+    rc::Rc,
+};
 
 #[derive(Debug, Clone)]
 pub enum Value {
-    Func(Box<FunctionContext>),
+    Func(Rc<FunctionContext>),
+    Closure(Rc<Closure>),
+    NativeFunc(Rc<NativeFunc>),
     Bool(bool),
     Int(i64),
+    Float(f64),
     String(String),
+    List(Rc<Vec<Value>>),
+    UserData(Rc<UserData>),
     Nil,
 }
+
+/// A function along with the variables it captured from the functions enclosing it.
+///
+/// Closures are reference counted. A nested function refers to itself without capturing
+/// anything, but nested functions that call each other capture each other's variables. Their
+/// closures then hold each other and are never freed.
+pub struct Closure {
+    pub func: Rc<FunctionContext>,
+    pub upvalues: Vec<Rc<RefCell<Value>>>,
+}
+
+/// A function implemented by the host.
+pub struct NativeFunc {
+    pub name: String,
+    /// Number of arguments the function takes, or `None` if it takes any number.
+    pub arity: Option<usize>,
+    #[allow(clippy::type_complexity)]
+    pub func: Box<dyn Fn(&[Value]) -> Result<Value, Box<dyn Error>>>,
+}
+
+/// A host object that scripts can hold, and use through the fields and methods of its type.
+pub struct UserData {
+    pub ty: Rc<UserType>,
+    pub data: RefCell<Box<dyn Any>>,
+}
+
+/// Describes a host type of `UserData`.
+pub struct UserType {
+    pub name: &'static str,
+    pub fields: HashMap<String, Getter>,
+    pub methods: HashMap<String, Method>,
+    /// Called with the data when the last reference to a value of this type is dropped.
+    #[allow(clippy::type_complexity)]
+    pub drop: Option<Box<dyn Fn(&mut dyn Any)>>,
+}
+
+/// Gives the value of a field of a `UserType` from the data.
+pub type Getter = Box<dyn Fn(&dyn Any) -> Value>;
+
+/// A method of a `UserType`, called with the data of the receiver and the arguments.
+pub struct Method {
+    /// Number of arguments the method takes, or `None` if it takes any number.
+    pub arity: Option<usize>,
+    #[allow(clippy::type_complexity)]
+    pub func: Rc<dyn Fn(&mut dyn Any, &[Value]) -> Result<Value, Box<dyn Error>>>,
+}
+
+impl UserData {
+    pub fn new(ty: Rc<UserType>, data: Box<dyn Any>) -> Self {
+        Self {
+            ty,
+            data: RefCell::new(data),
+        }
+    }
+
+    pub fn is<T: Any>(&self) -> bool {
+        self.data.try_borrow().is_ok_and(|data| data.is::<T>())
+    }
+
+    /// Returns the data if it is a `T` that is not mutably borrowed.
+    pub fn borrow<T: Any>(&self) -> Option<Ref<'_, T>> {
+        let data = self.data.try_borrow().ok()?;
+        Ref::filter_map(data, |data| data.downcast_ref()).ok()
+    }
+
+    /// Returns the data if it is a `T` that is not borrowed.
+    pub fn borrow_mut<T: Any>(&self) -> Option<RefMut<'_, T>> {
+        let data = self.data.try_borrow_mut().ok()?;
+        RefMut::filter_map(data, |data| data.downcast_mut()).ok()
+    }
+}
+
+impl UserType {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            fields: HashMap::new(),
+            methods: HashMap::new(),
+            drop: None,
+        }
+    }
+}
+
+impl Drop for UserData {
+    fn drop(&mut self) {
+        if let Some(drop) = &self.ty.drop {
+            drop(self.data.get_mut().as_mut());
+        }
+    }
+}
+
+impl Value {
+    /// Returns the name of the value's type, as shown in error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Func(_) | Self::Closure(_) | Self::NativeFunc(_) => "func",
+            Self::Bool(_) => "bool",
+            Self::Int(_) => "int",
+            Self::Float(_) => "float",
+            Self::String(_) => "string",
+            Self::List(_) => "list",
+            Self::UserData(data) => data.ty.name,
+            Self::Nil => "nil",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Func(func) => write!(f, "<func {}>", func.name),
+            Self::Closure(closure) => write!(f, "<func {}>", closure.func.name),
+            Self::NativeFunc(func) => write!(f, "<native func {}>", func.name),
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Int(i) => write!(f, "{}", i),
+            Self::Float(x) => write!(f, "{:?}", x),
+            Self::String(s) => write!(f, "{}", s),
+            Self::List(elems) => {
+                write!(f, "[")?;
+                for (i, elem) in elems.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", elem)?;
+                }
+                write!(f, "]")
+            }
+            Self::UserData(data) => write!(f, "<{}>", data.ty.name),
+            Self::Nil => write!(f, "nil"),
+        }
+    }
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The upvalues may hold the closure itself.
+        f.debug_struct("Closure")
+            .field("func", &self.func.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for NativeFunc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NativeFunc")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for UserData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserData")
+            .field("ty", &self.ty.name)
+            .finish_non_exhaustive()
+    }
+}